@@ -0,0 +1,28 @@
+use crate::filters::biquad::BiquadFilter;
+use crate::midi::{MidiNote, NoteEvent};
+
+/// The number of commands that can be waiting for the audio thread at once
+pub const COMMAND_QUEUE_SIZE: usize = 256;
+
+/// A message from the user interface (or any other control thread) to the audio thread
+///
+/// Commands are sent through a `queue::Producer` so the audio thread never has to lock
+/// anything to find out about key presses and parameter changes.
+#[derive(Clone, Debug)]
+pub enum Command {
+    NoteOn(NoteEvent),
+    NoteOff(MidiNote),
+    AllNotesOff,
+    /// Moves the output filter to the coefficients of the given filter, using its smoothing
+    SetFilter(BiquadFilter),
+    /// Bends every voice, from -1.0 (all the way down) to 1.0 (all the way up)
+    PitchBend(f64),
+    /// Sets how many semitones a full pitch bend moves
+    SetPitchBendRange(f64),
+    /// Tunes the given voice by the given number of cents
+    SetVoiceDetune(usize, f64),
+    /// Sets how far the voices are spread around their pan positions
+    SetSpread(f64),
+    /// Sets the stereo width of the output
+    SetWidth(f64),
+}
@@ -0,0 +1,254 @@
+use crate::random::Random;
+
+/// A type audio samples can be written out as
+pub trait OutputSample: Copy {
+    /// Whether the format stores whole numbers, so it has to be rounded and can be dithered
+    const INTEGER: bool;
+    /// The number of output steps between 0.0 and 1.0, or 1.0 for float formats
+    const SCALE: f64;
+
+    /// Converts a sample that has already been multiplied by `SCALE` (and rounded, for integer
+    /// formats), clamping it to the range of the format
+    fn from_scaled(x: f64) -> Self;
+}
+
+impl OutputSample for f32 {
+    const INTEGER: bool = false;
+    const SCALE: f64 = 1.0;
+
+    fn from_scaled(x: f64) -> Self {
+        x as f32
+    }
+}
+
+impl OutputSample for f64 {
+    const INTEGER: bool = false;
+    const SCALE: f64 = 1.0;
+
+    fn from_scaled(x: f64) -> Self {
+        x
+    }
+}
+
+impl OutputSample for i8 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 128.0;
+
+    fn from_scaled(x: f64) -> Self {
+        x.clamp(f64::from(i8::MIN), f64::from(i8::MAX)) as i8
+    }
+}
+
+impl OutputSample for u8 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 128.0;
+
+    fn from_scaled(x: f64) -> Self {
+        (x + 128.0).clamp(0.0, f64::from(u8::MAX)) as u8
+    }
+}
+
+impl OutputSample for i16 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 32768.0;
+
+    fn from_scaled(x: f64) -> Self {
+        x.clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16
+    }
+}
+
+impl OutputSample for u16 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 32768.0;
+
+    fn from_scaled(x: f64) -> Self {
+        (x + 32768.0).clamp(0.0, f64::from(u16::MAX)) as u16
+    }
+}
+
+impl OutputSample for i32 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 2_147_483_648.0;
+
+    fn from_scaled(x: f64) -> Self {
+        x.clamp(f64::from(i32::MIN), f64::from(i32::MAX)) as i32
+    }
+}
+
+/// A 24 bit signed integer sample, as stored in WAV files, held in the low bits of an i32
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct I24(pub i32);
+
+impl I24 {
+    pub const MIN: i32 = -(1 << 23);
+    pub const MAX: i32 = (1 << 23) - 1;
+
+    /// The three bytes of the sample, least significant first
+    pub fn to_le_bytes(self) -> [u8; 3] {
+        let bytes = self.0.to_le_bytes();
+        [bytes[0], bytes[1], bytes[2]]
+    }
+}
+
+impl OutputSample for I24 {
+    const INTEGER: bool = true;
+    const SCALE: f64 = 8_388_608.0;
+
+    fn from_scaled(x: f64) -> Self {
+        I24(x.clamp(f64::from(I24::MIN), f64::from(I24::MAX)) as i32)
+    }
+}
+
+/// How samples outside of -1.0 to 1.0 are brought back into range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipMode {
+    /// Samples are cut off at -1.0 and 1.0, anything in range is left alone
+    Hard,
+    /// Samples are passed through tanh, which rounds off peaks gradually, at the cost of
+    /// slightly squashing everything
+    Soft,
+}
+
+impl ClipMode {
+    pub fn clip(self, x: f64) -> f64 {
+        match self {
+            ClipMode::Hard => x.clamp(-1.0, 1.0),
+            ClipMode::Soft => x.tanh(),
+        }
+    }
+}
+
+/// Noise added to integer outputs before rounding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    /// Plain rounding, which leaves the rounding error correlated with the signal
+    None,
+    /// Triangular probability density noise of +/- 1 step, which turns the rounding error into
+    /// a constant, signal independent hiss
+    Tpdf,
+}
+
+/// # Sample Converter
+///
+/// Writes interleaved f32 samples out as any `OutputSample` type, clipping them, and for
+/// integer types, rounding them with optional dither and noise shaping
+///
+/// Noise shaping feeds the rounding error of each sample back into the next one on the same
+/// channel, which moves the noise up towards high frequencies where it is harder to hear.
+///
+/// ```rust
+/// # use sound_test::convert::{ClipMode, Dither, SampleConverter};
+/// let mut converter = SampleConverter::new(2);
+/// converter.set_clip_mode(ClipMode::Hard);
+///
+/// let input = [0.5, -0.5, 2.0, -2.0];
+/// let mut output = [0i16; 4];
+/// converter.convert(&input, &mut output);
+/// assert_eq!(output, [16384, -16384, i16::MAX, i16::MIN]);
+///
+/// // Dither changes the low bits, but never by more than a couple of steps
+/// converter.set_dither(Dither::Tpdf);
+/// converter.set_noise_shaping(true);
+/// converter.convert(&input[..2], &mut output[..2]);
+/// assert!((i32::from(output[0]) - 16384).abs() <= 3);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SampleConverter {
+    /// The number of interleaved channels
+    channels: usize,
+    clip_mode: ClipMode,
+    dither: Dither,
+    noise_shaping: bool,
+    /// The noise source for dither
+    random: Random,
+    /// The last rounding error on each channel, in output steps, used for noise shaping
+    errors: Vec<f64>,
+}
+
+impl SampleConverter {
+    /// Creates a SampleConverter for the given number of interleaved channels, with hard
+    /// clipping and no dither
+    pub fn new(channels: usize) -> Self {
+        let channels = channels.max(1);
+        SampleConverter {
+            channels,
+            clip_mode: ClipMode::Hard,
+            dither: Dither::None,
+            noise_shaping: false,
+            random: Random::default(),
+            errors: vec![0.0; channels],
+        }
+    }
+
+    pub fn get_channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn set_clip_mode(&mut self, clip_mode: ClipMode) {
+        self.clip_mode = clip_mode;
+    }
+
+    pub fn get_clip_mode(&self) -> ClipMode {
+        self.clip_mode
+    }
+
+    /// Sets the dither added to integer outputs, float outputs are never dithered
+    pub fn set_dither(&mut self, dither: Dither) {
+        self.dither = dither;
+    }
+
+    pub fn get_dither(&self) -> Dither {
+        self.dither
+    }
+
+    /// Sets whether rounding error is shaped towards high frequencies, for integer outputs
+    pub fn set_noise_shaping(&mut self, noise_shaping: bool) {
+        self.noise_shaping = noise_shaping;
+        if !noise_shaping {
+            self.reset();
+        }
+    }
+
+    pub fn get_noise_shaping(&self) -> bool {
+        self.noise_shaping
+    }
+
+    /// Forgets the rounding error carried over for noise shaping
+    pub fn reset(&mut self) {
+        for error in self.errors.iter_mut() {
+            *error = 0.0;
+        }
+    }
+
+    /// Converts a single sample on the given channel
+    pub fn convert_sample<T: OutputSample>(&mut self, x: f64, channel: usize) -> T {
+        let clipped = self.clip_mode.clip(x);
+        if !T::INTEGER {
+            return T::from_scaled(clipped * T::SCALE);
+        }
+
+        let mut target = clipped * T::SCALE;
+        let channel = channel % self.channels;
+        if self.noise_shaping {
+            target -= self.errors[channel];
+        }
+        let noise = match self.dither {
+            Dither::None => 0.0,
+            Dither::Tpdf => self.random.next_f64() - self.random.next_f64(),
+        };
+        let rounded = (target + noise).round();
+        if self.noise_shaping {
+            self.errors[channel] = rounded - target;
+        }
+
+        T::from_scaled(rounded)
+    }
+
+    /// Converts the interleaved input into the output, stopping at the end of whichever is
+    /// shorter
+    pub fn convert<T: OutputSample>(&mut self, input: &[f32], output: &mut [T]) {
+        for (i, (x, out)) in input.iter().zip(output.iter_mut()).enumerate() {
+            *out = self.convert_sample(f64::from(*x), i % self.channels);
+        }
+    }
+}
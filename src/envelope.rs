@@ -0,0 +1,345 @@
+/// The shape of the attack, decay and release segments of an envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvelopeCurve {
+    /// Segments move towards their target by a constant amount every sample
+    Linear,
+    /// Segments approach their target like an RC circuit charging, quickly at first and then
+    /// more slowly
+    Exponential,
+}
+
+/// What happens when the gate is opened while the envelope is still sounding
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerMode {
+    /// Every gate on restarts the attack, from the current level so that there is no click
+    Retrigger,
+    /// A gate on while the envelope is in attack, decay or sustain carries on from where it
+    /// is, only a released or idle envelope restarts the attack
+    Legato,
+}
+
+/// The stage an envelope is currently in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvelopeStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// How far past the target an exponential attack aims, as a fraction of the full scale
+///
+/// An exponential curve never actually reaches its target, so it is aimed a bit past the target
+/// and cut off when it gets there. Smaller values give a more curved segment. The attack is
+/// aimed much further than the other segments, like the charging capacitor of an analog
+/// envelope, which gives a fast but not instant rise.
+const ATTACK_OVERSHOOT: f64 = 0.3;
+/// How far past the target an exponential decay or release aims, as a fraction of the full scale
+const DECAY_OVERSHOOT: f64 = 0.001;
+
+/// # ADSR Envelope
+///
+/// A sample accurate Attack / Decay / Sustain / Release envelope generator
+///
+/// The attack, decay and release times are in seconds. The attack time is the time taken to
+/// rise from 0 to 1, and the decay and release times are the time taken to reach the sustain
+/// level and silence from wherever the envelope is when they start. The sustain level is
+/// between 0.0 and 1.0.
+///
+/// ```rust
+/// # use sound_test::envelope::{Envelope, EnvelopeStage};
+/// let mut envelope = Envelope::new(1000, 0.01, 0.01, 0.5, 0.01);
+/// envelope.gate_on();
+/// for _ in 0..30 {
+///     envelope.step();
+/// }
+/// assert_eq!(envelope.get_stage(), EnvelopeStage::Sustain);
+/// assert_eq!(envelope.get_level(), 0.5);
+///
+/// // The envelope keeps going until the release has finished
+/// envelope.gate_off();
+/// envelope.step();
+/// assert!(envelope.is_active());
+/// for _ in 0..10 {
+///     envelope.step();
+/// }
+/// assert!(!envelope.is_active());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    /// Attack time in seconds
+    attack: f64,
+    /// Decay time in seconds
+    decay: f64,
+    /// Sustain level
+    sustain: f64,
+    /// Release time in seconds
+    release: f64,
+    /// Shape of the segments
+    curve: EnvelopeCurve,
+    /// Behaviour when the gate is opened while the envelope is sounding
+    trigger_mode: TriggerMode,
+    /// Sample rate of the audio stream
+    sample_rate: u64,
+
+    /// Current stage
+    stage: EnvelopeStage,
+    /// Current output level
+    level: f64,
+    /// Level the current stage is heading towards
+    target: f64,
+    /// Per sample increment for linear segments, or multiplier for exponential segments
+    coefficient: f64,
+    /// Per sample offset for exponential segments
+    offset: f64,
+}
+
+impl Default for Envelope {
+    /// Creates an envelope that acts as a plain gate: full level as soon as the gate opens and
+    /// silence as soon as it closes
+    fn default() -> Self {
+        Envelope {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+            curve: EnvelopeCurve::Linear,
+            trigger_mode: TriggerMode::Retrigger,
+            sample_rate: 44100,
+            stage: EnvelopeStage::Idle,
+            level: 0.0,
+            target: 0.0,
+            coefficient: 0.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl Envelope {
+    /// Creates a new linear, retriggering envelope with the given times (in seconds) and
+    /// sustain level
+    pub fn new(sample_rate: u64, attack: f64, decay: f64, sustain: f64, release: f64) -> Self {
+        Envelope {
+            attack: attack.max(0.0),
+            decay: decay.max(0.0),
+            sustain: sustain.clamp(0.0, 1.0),
+            release: release.max(0.0),
+            sample_rate,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_attack(&mut self, attack: f64) {
+        self.attack = attack.max(0.0);
+        if self.stage == EnvelopeStage::Attack {
+            self.enter_stage(EnvelopeStage::Attack);
+        }
+    }
+
+    pub fn get_attack(&self) -> f64 {
+        self.attack
+    }
+
+    pub fn set_decay(&mut self, decay: f64) {
+        self.decay = decay.max(0.0);
+        if self.stage == EnvelopeStage::Decay {
+            self.enter_stage(EnvelopeStage::Decay);
+        }
+    }
+
+    pub fn get_decay(&self) -> f64 {
+        self.decay
+    }
+
+    pub fn set_sustain(&mut self, sustain: f64) {
+        self.sustain = sustain.clamp(0.0, 1.0);
+        match self.stage {
+            EnvelopeStage::Decay => self.enter_stage(EnvelopeStage::Decay),
+            EnvelopeStage::Sustain => self.level = self.sustain,
+            _ => (),
+        }
+    }
+
+    pub fn get_sustain(&self) -> f64 {
+        self.sustain
+    }
+
+    pub fn set_release(&mut self, release: f64) {
+        self.release = release.max(0.0);
+        if self.stage == EnvelopeStage::Release {
+            self.enter_stage(EnvelopeStage::Release);
+        }
+    }
+
+    pub fn get_release(&self) -> f64 {
+        self.release
+    }
+
+    pub fn set_curve(&mut self, curve: EnvelopeCurve) {
+        self.curve = curve;
+        self.enter_stage(self.stage);
+    }
+
+    pub fn get_curve(&self) -> EnvelopeCurve {
+        self.curve
+    }
+
+    pub fn set_trigger_mode(&mut self, trigger_mode: TriggerMode) {
+        self.trigger_mode = trigger_mode;
+    }
+
+    pub fn get_trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.sample_rate = sample_rate;
+        self.enter_stage(self.stage);
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    /// The stage the envelope is in
+    pub fn get_stage(&self) -> EnvelopeStage {
+        self.stage
+    }
+
+    /// The last level output by the envelope
+    pub fn get_level(&self) -> f64 {
+        self.level
+    }
+
+    /// Whether the envelope is producing any output, which includes the release stage
+    pub fn is_active(&self) -> bool {
+        self.stage != EnvelopeStage::Idle
+    }
+
+    /// Opens the gate, starting the attack stage (depending on the trigger mode)
+    pub fn gate_on(&mut self) {
+        match (self.trigger_mode, self.stage) {
+            (TriggerMode::Legato, EnvelopeStage::Attack)
+            | (TriggerMode::Legato, EnvelopeStage::Decay)
+            | (TriggerMode::Legato, EnvelopeStage::Sustain) => (),
+            _ => self.enter_stage(EnvelopeStage::Attack),
+        }
+    }
+
+    /// Closes the gate, starting the release stage
+    pub fn gate_off(&mut self) {
+        if self.stage != EnvelopeStage::Idle {
+            self.enter_stage(EnvelopeStage::Release);
+        }
+    }
+
+    /// Immediately silences the envelope, skipping the release stage
+    pub fn reset(&mut self) {
+        self.level = 0.0;
+        self.enter_stage(EnvelopeStage::Idle);
+    }
+
+    /// Sets up the target and coefficients for the given stage
+    fn enter_stage(&mut self, stage: EnvelopeStage) {
+        self.stage = stage;
+        let (target, time) = match stage {
+            EnvelopeStage::Idle => {
+                self.target = 0.0;
+                return;
+            }
+            EnvelopeStage::Sustain => {
+                self.target = self.sustain;
+                return;
+            }
+            EnvelopeStage::Attack => (1.0, self.attack),
+            EnvelopeStage::Decay => (self.sustain, self.decay),
+            EnvelopeStage::Release => (0.0, self.release),
+        };
+        // The attack always moves at the rate of a full 0 to 1 rise, so a retriggered attack
+        // is shorter, but decay and release take their full time from wherever they start
+        let range = if stage == EnvelopeStage::Attack {
+            1.0
+        } else {
+            (target - self.level).abs()
+        };
+        self.target = target;
+
+        let samples = time * self.sample_rate as f64;
+        match self.curve {
+            EnvelopeCurve::Linear => {
+                self.coefficient = if samples < 1.0 {
+                    range
+                } else {
+                    range / samples
+                };
+                self.offset = 0.0;
+            }
+            EnvelopeCurve::Exponential => {
+                // Aim past the target so the segment covers its range in the given time, the
+                // same way the linear segments do
+                let ratio = if stage == EnvelopeStage::Attack {
+                    ATTACK_OVERSHOOT
+                } else {
+                    DECAY_OVERSHOOT
+                };
+                let overshoot = if target > self.level { ratio } else { -ratio };
+                self.coefficient = if samples < 1.0 {
+                    0.0
+                } else {
+                    (-((range + ratio) / ratio).ln() / samples).exp()
+                };
+                self.offset = (target + overshoot) * (1.0 - self.coefficient);
+            }
+        }
+    }
+
+    /// Moves the level one sample towards the target, returning true once it has arrived
+    fn move_towards_target(&mut self) -> bool {
+        let rising = self.target > self.level;
+        match self.curve {
+            EnvelopeCurve::Linear => {
+                if rising {
+                    self.level += self.coefficient;
+                } else {
+                    self.level -= self.coefficient;
+                }
+            }
+            EnvelopeCurve::Exponential => {
+                self.level = self.offset + self.level * self.coefficient;
+            }
+        }
+
+        if (rising && self.level >= self.target) || (!rising && self.level <= self.target) {
+            self.level = self.target;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Steps the envelope, and returns the next output level
+    pub fn step(&mut self) -> f64 {
+        match self.stage {
+            EnvelopeStage::Idle => (),
+            EnvelopeStage::Sustain => self.level = self.sustain,
+            EnvelopeStage::Attack => {
+                if self.move_towards_target() {
+                    self.enter_stage(EnvelopeStage::Decay);
+                }
+            }
+            EnvelopeStage::Decay => {
+                if self.move_towards_target() {
+                    self.enter_stage(EnvelopeStage::Sustain);
+                }
+            }
+            EnvelopeStage::Release => {
+                if self.move_towards_target() {
+                    self.enter_stage(EnvelopeStage::Idle);
+                }
+            }
+        }
+
+        self.level
+    }
+}
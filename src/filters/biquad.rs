@@ -1,4 +1,9 @@
 use std::default;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::LineWriter;
+use std::ops::{Add, Div, Mul};
+
 /// # Biquad Filter
 ///
 /// Implements a "Biquad" (Biquadratic) filter with the transfer function
@@ -9,7 +14,7 @@ use std::default;
 ///
 /// y[n] = b0*x[n] + b1*x[n-1] + b2*x[n-2]  - a1*y[n-1] - a2*y[n-2]
 ///
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BiquadFilter {
     /// Filter coefficients
     /// b cofficients are for the input, and a is for the output
@@ -26,8 +31,47 @@ pub struct BiquadFilter {
     xn_2: f64,
     yn_1: f64,
     yn_2: f64,
+
+    /// How changes made with `retune` are smoothed
+    smoothing: CoefficientSmoothing,
+    /// Coefficients that `retune` is moving towards, in the order b0, b1, b2, a1, a2
+    target: [f64; 5],
+    /// Per sample change of each coefficient during a linear ramp
+    ramp_step: [f64; 5],
+    /// Samples left in the current linear ramp
+    ramp_remaining: usize,
+    /// Whether exponential smoothing is still moving towards the target
+    smoothing_active: bool,
+}
+
+/// How a `BiquadFilter` moves to new coefficients given to `retune`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoefficientSmoothing {
+    /// Jump to the new coefficients straight away
+    None,
+    /// Interpolate linearly to the new coefficients over the given number of samples
+    Linear(usize),
+    /// Move a fraction of the remaining distance to the new coefficients every sample, like a
+    /// one pole low pass filter. Use `CoefficientSmoothing::time_constant` to set this up from
+    /// a time
+    Exponential(f64),
+}
+
+impl CoefficientSmoothing {
+    /// Exponential smoothing that covers about 63% of a change in the given time, in seconds
+    pub fn time_constant(time: f64, sample_rate: f64) -> Self {
+        let samples = time * sample_rate;
+        if samples < 1.0 {
+            CoefficientSmoothing::None
+        } else {
+            CoefficientSmoothing::Exponential(1.0 - (-1.0 / samples).exp())
+        }
+    }
 }
 
+/// Coefficients closer than this to their target are snapped to it, ending exponential smoothing
+const SMOOTHING_THRESHOLD: f64 = 1e-9;
+
 impl default::Default for BiquadFilter {
     /// Creates a default BiquadFilter with everything zeroed
     fn default() -> Self {
@@ -41,6 +85,11 @@ impl default::Default for BiquadFilter {
             xn_2: 0.0,
             yn_1: 0.0,
             yn_2: 0.0,
+            smoothing: CoefficientSmoothing::None,
+            target: [0.0; 5],
+            ramp_step: [0.0; 5],
+            ramp_remaining: 0,
+            smoothing_active: false,
         }
     }
 }
@@ -54,10 +103,7 @@ impl BiquadFilter {
             b2,
             a1,
             a2,
-            xn_1: 0.0,
-            xn_2: 0.0,
-            yn_1: 0.0,
-            yn_2: 0.0,
+            ..Self::default()
         }
     }
 
@@ -77,10 +123,7 @@ impl BiquadFilter {
             a1: -2.0 * cos_omega_naught / (1.0 + alpha),
             a2: (1.0 - alpha) / (1.0 + alpha),
 
-            xn_1: 0.0,
-            xn_2: 0.0,
-            yn_1: 0.0,
-            yn_2: 0.0,
+            ..Self::default()
         }
     }
 
@@ -100,15 +143,275 @@ impl BiquadFilter {
             a1: -2.0 * cos_omega_naught / (1.0 + alpha),
             a2: (1.0 - alpha) / (1.0 + alpha),
 
-            xn_1: 0.0,
-            xn_2: 0.0,
-            yn_1: 0.0,
-            yn_2: 0.0,
+            ..Self::default()
+        }
+    }
+
+    /// Creates a BiquadFilter from un-normalized coefficients, dividing them all by a0
+    fn from_unnormalized(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> BiquadFilter {
+        BiquadFilter::new(b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0)
+    }
+
+    /// Computes the sine and cosine of the normalized angular frequency, and the alpha term used
+    /// by the designs below
+    fn design_parameters(frequency: f64, sample_rate: f64, quality: f64) -> (f64, f64, f64) {
+        let omega_naught = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        let sin_omega_naught = omega_naught.sin();
+        let alpha = sin_omega_naught / (2.0 * quality);
+        (sin_omega_naught, omega_naught.cos(), alpha)
+    }
+
+    /// Creates a BiquadFilter set up as a band pass filter with the given center frequency at
+    /// the given sample rate, whose peak gain is equal to the quality
+    pub fn band_pass_constant_skirt(
+        frequency: f64,
+        sample_rate: f64,
+        quality: f64,
+    ) -> BiquadFilter {
+        let (sin_omega_naught, cos_omega_naught, alpha) =
+            Self::design_parameters(frequency, sample_rate, quality);
+
+        Self::from_unnormalized(
+            sin_omega_naught / 2.0,
+            0.0,
+            -sin_omega_naught / 2.0,
+            1.0 + alpha,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as a band pass filter with the given center frequency at
+    /// the given sample rate, with a peak gain of 0 dB
+    pub fn band_pass_constant_peak(frequency: f64, sample_rate: f64, quality: f64) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+
+        Self::from_unnormalized(
+            alpha,
+            0.0,
+            -alpha,
+            1.0 + alpha,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as a notch filter, which removes the given frequency at the
+    /// given sample rate. The quality controls how narrow the notch is
+    pub fn notch(frequency: f64, sample_rate: f64, quality: f64) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+
+        Self::from_unnormalized(
+            1.0,
+            -2.0 * cos_omega_naught,
+            1.0,
+            1.0 + alpha,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as an all pass filter, which passes every frequency at
+    /// unity gain but shifts the phase by 180 degrees at the given frequency. The quality
+    /// controls how quickly the phase changes around that frequency
+    pub fn all_pass(frequency: f64, sample_rate: f64, quality: f64) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+
+        Self::from_unnormalized(
+            1.0 - alpha,
+            -2.0 * cos_omega_naught,
+            1.0 + alpha,
+            1.0 + alpha,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as a peaking EQ, which boosts or cuts (for a negative gain)
+    /// frequencies around the given frequency by the given gain in dB
+    pub fn peaking_eq(
+        frequency: f64,
+        sample_rate: f64,
+        quality: f64,
+        gain_db: f64,
+    ) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+        let amplitude = 10.0_f64.powf(gain_db / 40.0);
+
+        Self::from_unnormalized(
+            1.0 + alpha * amplitude,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha * amplitude,
+            1.0 + alpha / amplitude,
+            -2.0 * cos_omega_naught,
+            1.0 - alpha / amplitude,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as a low shelf, which boosts or cuts (for a negative gain)
+    /// frequencies below the given frequency by the given gain in dB. A quality of 1/sqrt(2)
+    /// gives the steepest slope without a bump at the corner
+    pub fn low_shelf(frequency: f64, sample_rate: f64, quality: f64, gain_db: f64) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+        let amplitude = 10.0_f64.powf(gain_db / 40.0);
+        let two_sqrt_amplitude_alpha = 2.0 * amplitude.sqrt() * alpha;
+
+        Self::from_unnormalized(
+            amplitude
+                * ((amplitude + 1.0) - (amplitude - 1.0) * cos_omega_naught
+                    + two_sqrt_amplitude_alpha),
+            2.0 * amplitude * ((amplitude - 1.0) - (amplitude + 1.0) * cos_omega_naught),
+            amplitude
+                * ((amplitude + 1.0)
+                    - (amplitude - 1.0) * cos_omega_naught
+                    - two_sqrt_amplitude_alpha),
+            (amplitude + 1.0) + (amplitude - 1.0) * cos_omega_naught + two_sqrt_amplitude_alpha,
+            -2.0 * ((amplitude - 1.0) + (amplitude + 1.0) * cos_omega_naught),
+            (amplitude + 1.0) + (amplitude - 1.0) * cos_omega_naught - two_sqrt_amplitude_alpha,
+        )
+    }
+
+    /// Creates a BiquadFilter set up as a high shelf, which boosts or cuts (for a negative gain)
+    /// frequencies above the given frequency by the given gain in dB. A quality of 1/sqrt(2)
+    /// gives the steepest slope without a bump at the corner
+    pub fn high_shelf(
+        frequency: f64,
+        sample_rate: f64,
+        quality: f64,
+        gain_db: f64,
+    ) -> BiquadFilter {
+        let (_, cos_omega_naught, alpha) = Self::design_parameters(frequency, sample_rate, quality);
+        let amplitude = 10.0_f64.powf(gain_db / 40.0);
+        let two_sqrt_amplitude_alpha = 2.0 * amplitude.sqrt() * alpha;
+
+        Self::from_unnormalized(
+            amplitude
+                * ((amplitude + 1.0)
+                    + (amplitude - 1.0) * cos_omega_naught
+                    + two_sqrt_amplitude_alpha),
+            -2.0 * amplitude * ((amplitude - 1.0) + (amplitude + 1.0) * cos_omega_naught),
+            amplitude
+                * ((amplitude + 1.0) + (amplitude - 1.0) * cos_omega_naught
+                    - two_sqrt_amplitude_alpha),
+            (amplitude + 1.0) - (amplitude - 1.0) * cos_omega_naught + two_sqrt_amplitude_alpha,
+            2.0 * ((amplitude - 1.0) - (amplitude + 1.0) * cos_omega_naught),
+            (amplitude + 1.0) - (amplitude - 1.0) * cos_omega_naught - two_sqrt_amplitude_alpha,
+        )
+    }
+
+    /// Sets how changes made with `retune` are smoothed
+    pub fn set_smoothing(&mut self, smoothing: CoefficientSmoothing) {
+        self.smoothing = smoothing;
+    }
+
+    pub fn get_smoothing(&self) -> CoefficientSmoothing {
+        self.smoothing
+    }
+
+    /// Whether the coefficients are still moving towards the last `retune`
+    pub fn is_smoothing(&self) -> bool {
+        self.ramp_remaining > 0 || self.smoothing_active
+    }
+
+    fn coefficients(&self) -> [f64; 5] {
+        [self.b0, self.b1, self.b2, self.a1, self.a2]
+    }
+
+    fn set_coefficients(&mut self, coefficients: [f64; 5]) {
+        self.b0 = coefficients[0];
+        self.b1 = coefficients[1];
+        self.b2 = coefficients[2];
+        self.a1 = coefficients[3];
+        self.a2 = coefficients[4];
+    }
+
+    /// Changes this filter to have the coefficients of the given filter, without clearing its
+    /// delay registers, so there is no click
+    ///
+    /// The change is smoothed according to the smoothing set with `set_smoothing`, which stops
+    /// zipper noise when the filter is swept. Every point on the way between two stable filters
+    /// is also stable, so the smoothing can never make the filter blow up.
+    ///
+    /// ```rust
+    /// # use sound_test::filters::biquad::{BiquadFilter, CoefficientSmoothing};
+    /// let mut filter = BiquadFilter::low_pass(1000.0, 44100.0, 0.707);
+    /// filter.set_smoothing(CoefficientSmoothing::Linear(64));
+    ///
+    /// // Sweep the cutoff up, once per block of 64 samples
+    /// for block in 0..10 {
+    ///     let cutoff = 1000.0 + 500.0 * block as f64;
+    ///     filter.retune(&BiquadFilter::low_pass(cutoff, 44100.0, 0.707));
+    ///     for _ in 0..64 {
+    ///         filter.step(0.5);
+    ///     }
+    /// }
+    /// assert!(!filter.is_smoothing());
+    /// ```
+    pub fn retune(&mut self, target: &BiquadFilter) {
+        self.target = target.coefficients();
+        self.ramp_remaining = 0;
+        self.smoothing_active = false;
+
+        match self.smoothing {
+            CoefficientSmoothing::Linear(samples) if samples > 0 => {
+                let current = self.coefficients();
+                for ((step, target), current) in self
+                    .ramp_step
+                    .iter_mut()
+                    .zip(self.target.iter())
+                    .zip(current.iter())
+                {
+                    *step = (target - current) / samples as f64;
+                }
+                self.ramp_remaining = samples;
+            }
+            CoefficientSmoothing::Exponential(amount) if amount > 0.0 && amount < 1.0 => {
+                self.smoothing_active = true;
+            }
+            _ => self.set_coefficients(self.target),
+        }
+    }
+
+    /// Moves the coefficients one sample towards the target given to `retune`
+    fn smooth_coefficients(&mut self) {
+        if self.ramp_remaining > 0 {
+            self.ramp_remaining -= 1;
+            if self.ramp_remaining == 0 {
+                // Land exactly on the target, whatever rounding happened on the way
+                self.set_coefficients(self.target);
+            } else {
+                let mut coefficients = self.coefficients();
+                for (coefficient, step) in coefficients.iter_mut().zip(self.ramp_step.iter()) {
+                    *coefficient += step;
+                }
+                self.set_coefficients(coefficients);
+            }
+        } else if self.smoothing_active {
+            if let CoefficientSmoothing::Exponential(amount) = self.smoothing {
+                let mut coefficients = self.coefficients();
+                let mut settled = true;
+                for (coefficient, target) in coefficients.iter_mut().zip(self.target.iter()) {
+                    *coefficient += (target - *coefficient) * amount;
+                    settled &= (target - *coefficient).abs() < SMOOTHING_THRESHOLD;
+                }
+                if settled {
+                    coefficients = self.target;
+                    self.smoothing_active = false;
+                }
+                self.set_coefficients(coefficients);
+            } else {
+                self.set_coefficients(self.target);
+                self.smoothing_active = false;
+            }
         }
     }
 
     /// Steps the filter using the given input sample, and returns the next output sample
     pub fn step(&mut self, x: f64) -> f64 {
+        if self.is_smoothing() {
+            self.smooth_coefficients();
+        }
+
         let y = self.b0 * x + self.b1 * self.xn_1 + self.b2 * self.xn_2
             - self.a1 * self.yn_1
             - self.a2 * self.yn_2;
@@ -133,4 +436,189 @@ impl BiquadFilter {
 
         ys
     }
+
+    /// Evaluates the transfer function at the given frequency and sample rate, returning the
+    /// complex gain of the filter at that frequency
+    pub fn response(&self, frequency: f64, sample_rate: f64) -> Complex {
+        let omega = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        // z^-1 and z^-2 on the unit circle
+        let z1 = Complex::from_polar(1.0, -omega);
+        let z2 = Complex::from_polar(1.0, -2.0 * omega);
+
+        let numerator = Complex::new(self.b0, 0.0) + z1 * self.b1 + z2 * self.b2;
+        let denominator = Complex::new(1.0, 0.0) + z1 * self.a1 + z2 * self.a2;
+        numerator / denominator
+    }
+
+    /// The gain of the filter at the given frequency, as a ratio
+    pub fn magnitude(&self, frequency: f64, sample_rate: f64) -> f64 {
+        self.response(frequency, sample_rate).norm()
+    }
+
+    /// The gain of the filter at the given frequency, in dB
+    pub fn magnitude_db(&self, frequency: f64, sample_rate: f64) -> f64 {
+        20.0 * self.magnitude(frequency, sample_rate).log10()
+    }
+
+    /// The phase shift of the filter at the given frequency, in radians between -pi and pi
+    pub fn phase(&self, frequency: f64, sample_rate: f64) -> f64 {
+        self.response(frequency, sample_rate).arg()
+    }
+
+    /// The group delay of the filter at the given frequency, in samples
+    ///
+    /// This is how long the envelope of a signal around that frequency is delayed by the filter.
+    pub fn group_delay(&self, frequency: f64, sample_rate: f64) -> f64 {
+        let omega = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        let z1 = Complex::from_polar(1.0, -omega);
+        let z2 = Complex::from_polar(1.0, -2.0 * omega);
+
+        // The group delay of a polynomial c0 + c1 z^-1 + c2 z^-2 is
+        // Re((c1 z^-1 + 2 c2 z^-2) / (c0 + c1 z^-1 + c2 z^-2))
+        let delay = |c0: f64, c1: f64, c2: f64| {
+            let polynomial = Complex::new(c0, 0.0) + z1 * c1 + z2 * c2;
+            let ramped = z1 * c1 + z2 * (2.0 * c2);
+            (ramped / polynomial).re
+        };
+
+        delay(self.b0, self.b1, self.b2) - delay(1.0, self.a1, self.a2)
+    }
+
+    /// The zeros of the transfer function, the roots of b0 z^2 + b1 z + b2
+    ///
+    /// If b0 is zero there are fewer than two zeros, and the missing ones are reported at
+    /// infinity.
+    pub fn zeros(&self) -> [Complex; 2] {
+        if self.b0 == 0.0 {
+            let infinity = Complex::new(f64::INFINITY, 0.0);
+            if self.b1 == 0.0 {
+                return [infinity, infinity];
+            }
+            return [Complex::new(-self.b2 / self.b1, 0.0), infinity];
+        }
+        quadratic_roots(self.b1 / self.b0, self.b2 / self.b0)
+    }
+
+    /// The poles of the transfer function, the roots of z^2 + a1 z + a2
+    pub fn poles(&self) -> [Complex; 2] {
+        quadratic_roots(self.a1, self.a2)
+    }
+
+    /// Whether the filter is stable, meaning both poles are inside the unit circle, so its
+    /// output dies away when the input stops
+    pub fn is_stable(&self) -> bool {
+        self.a2.abs() < 1.0 && self.a1.abs() < 1.0 + self.a2
+    }
+
+    /// Writes the response of the filter at the given number of points, spaced logarithmically
+    /// from 10 Hz to nyquist, to a file
+    ///
+    /// Each line holds the frequency, the magnitude in dB, the phase in radians and the group
+    /// delay in samples, separated by spaces.
+    pub fn dump_response_to_file(
+        &self,
+        file_name: &str,
+        sample_rate: f64,
+        points: usize,
+    ) -> std::io::Result<()> {
+        let file = File::create(file_name)?;
+        let mut file = LineWriter::new(file);
+
+        let lowest = 10.0_f64;
+        let highest = sample_rate / 2.0;
+        let ratio = (highest / lowest).ln();
+        for i in 0..points {
+            let position = if points > 1 {
+                i as f64 / (points - 1) as f64
+            } else {
+                0.0
+            };
+            let frequency = lowest * (ratio * position).exp();
+            writeln!(
+                file,
+                "{} {} {} {}",
+                frequency,
+                self.magnitude_db(frequency, sample_rate),
+                self.phase(frequency, sample_rate),
+                self.group_delay(frequency, sample_rate)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the two (possibly complex) roots of z^2 + p z + q
+fn quadratic_roots(p: f64, q: f64) -> [Complex; 2] {
+    let half_p = p / 2.0;
+    let discriminant = half_p * half_p - q;
+    if discriminant >= 0.0 {
+        let root = discriminant.sqrt();
+        [
+            Complex::new(-half_p + root, 0.0),
+            Complex::new(-half_p - root, 0.0),
+        ]
+    } else {
+        let root = (-discriminant).sqrt();
+        [Complex::new(-half_p, root), Complex::new(-half_p, -root)]
+    }
+}
+
+/// A complex number, used for the frequency response, poles and zeros of filters
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    /// Creates a complex number with the given magnitude and angle
+    pub fn from_polar(norm: f64, arg: f64) -> Self {
+        Complex {
+            re: norm * arg.cos(),
+            im: norm * arg.sin(),
+        }
+    }
+
+    /// The magnitude
+    pub fn norm(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// The angle, between -pi and pi
+    pub fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Mul<f64> for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: f64) -> Complex {
+        Complex::new(self.re * other, self.im * other)
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+
+    fn div(self, other: Complex) -> Complex {
+        let denominator = other.re * other.re + other.im * other.im;
+        Complex::new(
+            (self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator,
+        )
+    }
 }
@@ -0,0 +1,179 @@
+use crate::filters::MAX_CUTOFF_RATIO;
+
+/// Which output of the ladder to use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LadderMode {
+    /// The output of the second stage, a 12 dB/octave low pass
+    LowPass12,
+    /// The output of the fourth stage, the classic 24 dB/octave low pass
+    LowPass24,
+}
+
+/// The feedback gain at which the ladder starts to oscillate on its own
+const SELF_OSCILLATION_FEEDBACK: f64 = 4.0;
+/// The highest resonance allowed, a bit past self oscillation so it keeps going once started
+const MAX_RESONANCE: f64 = 1.2;
+/// The lowest drive allowed, which is practically linear
+const MIN_DRIVE: f64 = 0.01;
+
+/// # Ladder Filter
+///
+/// A 4 pole low pass filter modelled on the Moog transistor ladder, built from four
+/// trapezoidal one pole filters with a zero delay feedback loop around them
+///
+/// The resonance goes from 0.0 (no feedback) to 1.0, where the filter starts to oscillate on
+/// its own at the cutoff frequency. The input to the ladder is passed through a tanh
+/// saturation stage, which keeps the self oscillation from growing without bound and adds the
+/// warm distortion of the analog circuit when the drive is turned up.
+///
+/// ```rust
+/// # use sound_test::filters::ladder::LadderFilter;
+/// let mut filter = LadderFilter::new(500.0, 44100.0, 0.5);
+/// let mut peak: f64 = 0.0;
+/// for i in 0..44100 {
+///     // A 5 kHz square wave is far above the cutoff, so it is cut down a lot
+///     let x = if (i / 4) % 2 == 0 { 1.0 } else { -1.0 };
+///     let y = filter.step(x);
+///     if i > 1000 {
+///         peak = peak.max(y.abs());
+///     }
+/// }
+/// assert!(peak < 0.01);
+/// ```
+#[derive(Clone, Debug)]
+pub struct LadderFilter {
+    /// Cutoff frequency
+    frequency: f64,
+    /// Resonance, 1.0 is the edge of self oscillation
+    resonance: f64,
+    /// Gain into the saturation stage
+    drive: f64,
+    /// Sample rate of the audio stream
+    sample_rate: f64,
+    /// Which output to return from step
+    mode: LadderMode,
+
+    /// Gain of each one pole stage, g / (1 + g)
+    stage_gain: f64,
+    /// Feedback gain
+    feedback: f64,
+    /// State of each one pole stage
+    state: [f64; 4],
+}
+
+impl LadderFilter {
+    /// Creates a new 24 dB/octave LadderFilter with the given cutoff frequency at the given
+    /// sample rate, and with the given resonance
+    pub fn new(frequency: f64, sample_rate: f64, resonance: f64) -> Self {
+        let mut s = LadderFilter {
+            frequency,
+            resonance: resonance.clamp(0.0, MAX_RESONANCE),
+            drive: 1.0,
+            sample_rate,
+            mode: LadderMode::LowPass24,
+            stage_gain: 0.0,
+            feedback: 0.0,
+            state: [0.0; 4],
+        };
+        s.cook_coefficients();
+        s
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_coefficients();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn set_resonance(&mut self, resonance: f64) {
+        self.resonance = resonance.clamp(0.0, MAX_RESONANCE);
+        self.cook_coefficients();
+    }
+
+    pub fn get_resonance(&self) -> f64 {
+        self.resonance
+    }
+
+    /// Sets the gain into the saturation stage, lower values are cleaner and higher values
+    /// distort more. The output level is kept about the same whatever the drive
+    pub fn set_drive(&mut self, drive: f64) {
+        self.drive = drive.max(MIN_DRIVE);
+    }
+
+    pub fn get_drive(&self) -> f64 {
+        self.drive
+    }
+
+    pub fn set_mode(&mut self, mode: LadderMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> LadderMode {
+        self.mode
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.cook_coefficients();
+    }
+
+    pub fn get_sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Clears the stages, silencing any ringing or self oscillation
+    pub fn reset(&mut self) {
+        self.state = [0.0; 4];
+    }
+
+    fn cook_coefficients(&mut self) {
+        let frequency = self
+            .frequency
+            .clamp(0.0, MAX_CUTOFF_RATIO * self.sample_rate);
+        let g = (std::f64::consts::PI * frequency / self.sample_rate).tan();
+        self.stage_gain = g / (1.0 + g);
+        self.feedback = SELF_OSCILLATION_FEEDBACK * self.resonance;
+    }
+
+    /// Steps the filter using the given input sample, and returns the output of the selected
+    /// mode
+    pub fn step(&mut self, x: f64) -> f64 {
+        let (pole2, pole4) = self.step_outputs(x);
+        match self.mode {
+            LadderMode::LowPass12 => pole2,
+            LadderMode::LowPass24 => pole4,
+        }
+    }
+
+    /// Steps the filter using the given input sample, and returns both the 12 dB/octave and
+    /// 24 dB/octave outputs
+    pub fn step_outputs(&mut self, x: f64) -> (f64, f64) {
+        let g = self.stage_gain;
+
+        // The output of the last stage is G^4 u + (the contribution of the stored states), so
+        // the feedback loop can be solved for the input to the first stage without a delay
+        let mut state_sum = 0.0;
+        for s in self.state.iter() {
+            state_sum = state_sum * g + s * (1.0 - g);
+        }
+        let g4 = g * g * g * g;
+        let u = (x - self.feedback * state_sum) / (1.0 + self.feedback * g4);
+        let u = (self.drive * u).tanh() / self.drive;
+
+        let mut input = u;
+        let mut outputs = [0.0; 4];
+        for (s, output) in self.state.iter_mut().zip(outputs.iter_mut()) {
+            // Trapezoidal one pole low pass
+            let v = (input - *s) * g;
+            let y = v + *s;
+            *s = y + v;
+            *output = y;
+            input = y;
+        }
+
+        (outputs[1], outputs[3])
+    }
+}
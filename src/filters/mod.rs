@@ -1 +1,7 @@
 pub mod biquad;
+pub mod ladder;
+pub mod svf;
+
+/// The highest cutoff allowed, as a fraction of the sample rate, to keep the prewarping of the
+/// filters that use it finite
+pub(crate) const MAX_CUTOFF_RATIO: f64 = 0.49;
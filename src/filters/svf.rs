@@ -0,0 +1,141 @@
+use crate::filters::MAX_CUTOFF_RATIO;
+
+/// The outputs of a `StateVariableFilter`, all computed from the same input sample
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SvfOutput {
+    pub low_pass: f64,
+    pub high_pass: f64,
+    pub band_pass: f64,
+    pub notch: f64,
+}
+
+/// # State Variable Filter
+///
+/// A 12 dB/octave topology preserving transform (trapezoidal integration) state variable filter,
+/// after Andrew Simper's "Linear Trapezoidal Integrated SVF"
+///
+/// Unlike the direct form `BiquadFilter`, the state of this filter is the state of its two
+/// integrators, so the cutoff and quality can be changed every sample, even at audio rate,
+/// without the output blowing up or clicking. Low pass, high pass, band pass and notch outputs
+/// are all produced at once.
+///
+/// ```rust
+/// # use sound_test::filters::svf::StateVariableFilter;
+/// let mut filter = StateVariableFilter::new(1000.0, 44100.0, 0.707);
+/// // Sweep the cutoff every sample
+/// let mut last = Default::default();
+/// for i in 0..44100 {
+///     filter.set_frequency(200.0 + 5000.0 * (i as f64 / 1000.0).sin().abs());
+///     last = filter.step(1.0);
+/// }
+/// // A constant input passes through the low pass and is removed by the others
+/// assert!((last.low_pass - 1.0).abs() < 1e-6);
+/// assert!(last.high_pass.abs() < 1e-6);
+/// assert!(last.band_pass.abs() < 1e-6);
+/// ```
+#[derive(Clone, Debug)]
+pub struct StateVariableFilter {
+    /// Cutoff / center frequency
+    frequency: f64,
+    /// Quality, controls the resonance
+    quality: f64,
+    /// Sample rate of the audio stream
+    sample_rate: f64,
+
+    /// Prewarped integrator gain
+    g: f64,
+    /// Damping, the reciprocal of the quality
+    k: f64,
+    /// Coefficients derived from g and k
+    a1: f64,
+    a2: f64,
+    a3: f64,
+
+    /// Integrator states
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl StateVariableFilter {
+    /// Creates a new StateVariableFilter with the given cutoff frequency at the given sample
+    /// rate, and with the given quality (Controls how resonant the cutoff is)
+    pub fn new(frequency: f64, sample_rate: f64, quality: f64) -> Self {
+        let mut s = StateVariableFilter {
+            frequency,
+            quality,
+            sample_rate,
+            g: 0.0,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        s.cook_coefficients();
+        s
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_coefficients();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn set_quality(&mut self, quality: f64) {
+        self.quality = quality;
+        self.cook_coefficients();
+    }
+
+    pub fn get_quality(&self) -> f64 {
+        self.quality
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.cook_coefficients();
+    }
+
+    pub fn get_sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Clears the integrators, silencing any ringing
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+
+    fn cook_coefficients(&mut self) {
+        let frequency = self
+            .frequency
+            .clamp(0.0, MAX_CUTOFF_RATIO * self.sample_rate);
+        self.g = (std::f64::consts::PI * frequency / self.sample_rate).tan();
+        self.k = 1.0 / self.quality.max(f64::EPSILON);
+        self.a1 = 1.0 / (1.0 + self.g * (self.g + self.k));
+        self.a2 = self.g * self.a1;
+        self.a3 = self.g * self.a2;
+    }
+
+    /// Steps the filter using the given input sample, and returns the next output of each type
+    pub fn step(&mut self, x: f64) -> SvfOutput {
+        let v3 = x - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        let low_pass = v2;
+        let band_pass = v1;
+        let high_pass = x - self.k * v1 - v2;
+        SvfOutput {
+            low_pass,
+            high_pass,
+            band_pass,
+            notch: low_pass + high_pass,
+        }
+    }
+}
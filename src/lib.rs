@@ -1,3 +1,15 @@
+pub mod command;
+pub mod convert;
+pub mod envelope;
 pub mod filters;
 pub mod midi;
+pub mod mix;
 pub mod oscillator;
+pub mod queue;
+pub mod random;
+pub mod render;
+pub mod synth;
+pub mod tuning;
+pub mod velocity;
+pub mod voice;
+pub mod wav;
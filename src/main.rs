@@ -1,11 +1,11 @@
 #![allow(unused_imports)]
 
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
 use cpal::{StreamData, UnknownTypeOutputBuffer};
@@ -14,13 +14,63 @@ use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 
+use sound_test::command::{Command, COMMAND_QUEUE_SIZE};
+use sound_test::convert::{Dither, OutputSample, SampleConverter};
+use sound_test::envelope::Envelope;
 use sound_test::filters::biquad::BiquadFilter;
-use sound_test::midi::MidiNote;
+use sound_test::midi::smf::{MidiFile, MidiPlayer};
+use sound_test::midi::{MidiNote, NoteEvent, MAX_VELOCITY};
 use sound_test::oscillator::sine::SineOscillator;
 use sound_test::oscillator::wavetable::{
-    WaveTable, WaveTableOscillator, SAW_WAVE_TABLE, SINE_WAVE_TABLE, SQUARE_WAVE_TABLE,
+    WaveTable, WaveTableOscillator, BAND_LIMITED_SAW_WAVE_TABLE, BAND_LIMITED_SQUARE_WAVE_TABLE,
+    BAND_LIMITED_TRIANGLE_WAVE_TABLE, SAW_WAVE_TABLE, SINE_WAVE_TABLE, SQUARE_WAVE_TABLE,
     TRIANGLE_WAVE_TABLE,
 };
+use sound_test::queue::queue;
+use sound_test::synth::Synth;
+use sound_test::tuning::Tuning;
+use sound_test::velocity::{VelocityCurve, VelocityCutoff};
+use sound_test::voice::{Voice, VoiceAllocator};
+
+/// Size of the buffer the synth renders into before it is converted to the output format
+const SCRATCH_SIZE: usize = 1024;
+
+/// Whether every key still plays a valid MIDI note when shifted by the given number of semitones
+fn keymap_fits(keymap: &HashMap<Keycode, MidiNote>, transpose: i32) -> bool {
+    keymap
+        .values()
+        .all(|note| note.checked_transpose(transpose).is_some())
+}
+
+/// Whether the given file name ends with the given extension, ignoring case
+fn has_extension(file_name: &str, extension: &str) -> bool {
+    std::path::Path::new(file_name)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+/// Fills an interleaved output buffer of any sample type from the synth
+/// The note, with its frequency in the given tuning if the tuning plays it
+fn describe_note(note: MidiNote, tuning: &Tuning) -> String {
+    match tuning.to_frequency(note) {
+        Some(frequency) => format!("{}, frequency {:.2}", note, frequency),
+        None => format!("{}, not in the tuning", note),
+    }
+}
+
+fn write_output<V: Voice, T: OutputSample>(
+    synth: &mut Synth<V>,
+    converter: &mut SampleConverter,
+    output: &mut [T],
+) {
+    let channels = converter.get_channels();
+    let mut scratch = [0.0f32; SCRATCH_SIZE];
+    for chunk in output.chunks_mut(SCRATCH_SIZE / channels * channels) {
+        let rendered = &mut scratch[..chunk.len()];
+        synth.render(rendered, channels);
+        converter.convert(rendered, chunk);
+    }
+}
 
 fn main() {
     // Debug output of wave tables
@@ -36,6 +86,15 @@ fn main() {
     if let Err(e) = TRIANGLE_WAVE_TABLE.dump_to_file("triangle.dat") {
         println!("Could not dump triangle wave table: {}", e);
     }
+    if let Err(e) = BAND_LIMITED_SAW_WAVE_TABLE.dump_to_file("saw_band_limited.dat") {
+        println!("Could not dump band limited saw wave table: {}", e);
+    }
+    if let Err(e) = BAND_LIMITED_SQUARE_WAVE_TABLE.dump_to_file("square_band_limited.dat") {
+        println!("Could not dump band limited square wave table: {}", e);
+    }
+    if let Err(e) = BAND_LIMITED_TRIANGLE_WAVE_TABLE.dump_to_file("triangle_band_limited.dat") {
+        println!("Could not dump band limited triangle wave table: {}", e);
+    }
 
     let keymap: HashMap<Keycode, MidiNote> = [
         (Keycode::Z, MidiNote::new(36)),     // 'z' => C2
@@ -112,23 +171,64 @@ fn main() {
 
     println!("Audio format: {:?}", format);
 
-    let oscs: Arc<Mutex<Vec<WaveTableOscillator>>> = Arc::new(Mutex::new(vec![]));
     let max_polyphony = 16;
+    let mut oscs = vec![];
     for _ in 0..max_polyphony {
-        let osc = WaveTableOscillator::new(sample_rate, SAW_WAVE_TABLE.clone());
-        oscs.lock().unwrap().push(osc);
+        let mut osc =
+            WaveTableOscillator::new_band_limited(sample_rate, BAND_LIMITED_SAW_WAVE_TABLE.clone());
+        osc.set_envelope(Envelope::new(sample_rate, 0.01, 0.2, 0.7, 0.3));
+        oscs.push(osc);
+    }
+    // Create filter to test with
+    let filter = BiquadFilter::high_pass(200.0, sample_rate as f64, 0.1);
+    println!("{:?}", filter);
+    if let Err(e) = filter.dump_response_to_file("filter_response.dat", sample_rate as f64, 512) {
+        println!("Could not dump filter response: {}", e);
+    }
+
+    // The audio thread owns the synth, everything else talks to it through the command queue
+    let mut synth = Synth::new(sample_rate, VoiceAllocator::new(oscs));
+
+    // Tune to a Scala scale (and keyboard mapping) given on the command line
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(scl_file) = args.iter().find(|arg| has_extension(arg, "scl")) {
+        let kbm_file = args.iter().find(|arg| has_extension(arg, "kbm"));
+        match Tuning::from_scala_files(scl_file, kbm_file.map(String::as_str)) {
+            Ok(tuning) => {
+                println!("Using tuning {}", scl_file);
+                synth.set_tuning(tuning);
+            }
+            Err(e) => println!("Could not read tuning {}: {}", scl_file, e),
+        }
     }
+    synth.set_channels(format.channels as usize);
+    synth.set_filter(filter);
+    synth.set_spread(0.5);
+    synth.set_velocity_curve(VelocityCurve::Exponential(30.0));
+    synth.set_velocity_cutoff(Some(VelocityCutoff::new(
+        VelocityCurve::Linear,
+        800.0,
+        12000.0,
+    )));
+    let (mut commands, mut command_receiver) = queue(COMMAND_QUEUE_SIZE);
 
     // For testing purposes
     let mut transpose = 0;
+    let mut velocity: u8 = 100;
+    let mut held_keys = HashSet::new();
 
-    // Create filter to test with
-    let mut lp_filter = BiquadFilter::high_pass(200.0, sample_rate as f64, 0.1);
-    println!("{:?}", lp_filter);
+    // Kept for printing the frequencies of the keys played, the synth moves to the audio thread
+    let tuning = synth.get_tuning().clone();
 
-    let oscs_vec = oscs.clone();
+    let mut converter = SampleConverter::new(format.channels as usize);
+    converter.set_dither(Dither::Tpdf);
+    converter.set_noise_shaping(true);
     thread::spawn(move || {
         event_loop.run(move |stream_id, stream_result| {
+            while let Some(command) = command_receiver.pop() {
+                synth.handle_command(command);
+            }
+
             let stream_data = match stream_result {
                 Ok(data) => data,
                 Err(err) => {
@@ -140,65 +240,53 @@ fn main() {
             match stream_data {
                 StreamData::Output {
                     buffer: UnknownTypeOutputBuffer::U16(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let mut next_value: f64 = oscs_vec
-                            .lock()
-                            .unwrap()
-                            .iter_mut()
-                            .map(&WaveTableOscillator::step)
-                            .sum();
-                        next_value /= oscs_vec.lock().unwrap().len() as f64;
-                        next_value = lp_filter.step(next_value);
-                        let value = ((next_value * 0.5 + 0.5) * f64::from(std::u16::MAX)) as u16;
-                        for out in sample.iter_mut() {
-                            *out = value;
-                        }
-                    }
-                }
+                } => write_output(&mut synth, &mut converter, &mut buffer),
                 StreamData::Output {
                     buffer: UnknownTypeOutputBuffer::I16(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let mut next_value: f64 = oscs_vec
-                            .lock()
-                            .unwrap()
-                            .iter_mut()
-                            .map(&WaveTableOscillator::step)
-                            .sum();
-                        next_value /= oscs_vec.lock().unwrap().len() as f64;
-                        next_value = lp_filter.step(next_value);
-                        let value = (next_value * f64::from(std::i16::MAX)) as i16;
-                        for out in sample.iter_mut() {
-                            *out = value;
-                        }
-                    }
-                }
+                } => write_output(&mut synth, &mut converter, &mut buffer),
                 StreamData::Output {
                     buffer: UnknownTypeOutputBuffer::F32(mut buffer),
-                } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let mut next_value: f64 = oscs_vec
-                            .lock()
-                            .unwrap()
-                            .iter_mut()
-                            .map(&WaveTableOscillator::step)
-                            .sum();
-                        next_value /= oscs_vec.lock().unwrap().len() as f64;
-                        next_value = lp_filter.step(next_value);
-                        let value = next_value as f32;
-                        for out in sample.iter_mut() {
-                            *out = value;
-                        }
-                    }
-                }
+                } => write_output(&mut synth, &mut converter, &mut buffer),
                 _ => (),
             }
         });
     });
 
+    // Play a MIDI file given on the command line alongside the keyboard
+    let mut player = match args.iter().find(|arg| has_extension(arg, "mid")) {
+        Some(file_name) => match MidiFile::read(file_name) {
+            Ok(file) => {
+                let player = MidiPlayer::new(&file);
+                println!("Playing {} ({:.1} seconds)", file_name, player.duration());
+                Some(player)
+            }
+            Err(e) => {
+                println!("Could not read MIDI file {}: {}", file_name, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut last_frame = Instant::now();
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
+        let now = Instant::now();
+        if let Some(player) = player.as_mut() {
+            let elapsed = now.duration_since(last_frame).as_secs_f64();
+            for event in player.advance_events(elapsed) {
+                let command = if event.on {
+                    Command::NoteOn(NoteEvent::new(event.note, event.velocity))
+                } else {
+                    Command::NoteOff(event.note)
+                };
+                if commands.push(command).is_err() {
+                    println!("Command queue full, dropping MIDI event");
+                }
+            }
+        }
+        last_frame = now;
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
@@ -212,66 +300,85 @@ fn main() {
                     keycode: Some(Keycode::Backspace),
                     ..
                 } => {
-                    for osc in oscs.lock().unwrap().iter_mut() {
-                        if osc.is_playing() {
-                            osc.note_off();
-                        }
-                    }
+                    held_keys.clear();
+                    commands.push(Command::AllNotesOff).ok();
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::Up),
                     ..
-                } => {
-                    if !oscs.lock().unwrap().iter().any(|osc| osc.is_playing()) {
-                        transpose = min(72, transpose + 12);
-                    }
+                } if held_keys.is_empty() && keymap_fits(&keymap, transpose + 12) => {
+                    transpose += 12;
                 }
                 Event::KeyDown {
                     keycode: Some(Keycode::Down),
                     ..
+                } if held_keys.is_empty() && keymap_fits(&keymap, transpose - 12) => {
+                    transpose -= 12;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Left),
+                    ..
                 } => {
-                    if !oscs.lock().unwrap().iter().any(|osc| osc.is_playing()) {
-                        transpose = max(-36, transpose - 12);
-                    }
+                    velocity = max(1, velocity.saturating_sub(16));
+                    println!("Velocity {}", velocity);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Right),
+                    ..
+                } => {
+                    velocity = min(MAX_VELOCITY, velocity.saturating_add(16));
+                    println!("Velocity {}", velocity);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::LeftBracket),
+                    repeat: false,
+                    ..
+                } => {
+                    commands.push(Command::PitchBend(-1.0)).ok();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::RightBracket),
+                    repeat: false,
+                    ..
+                } => {
+                    commands.push(Command::PitchBend(1.0)).ok();
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::LeftBracket),
+                    ..
+                }
+                | Event::KeyUp {
+                    keycode: Some(Keycode::RightBracket),
+                    ..
+                } => {
+                    commands.push(Command::PitchBend(0.0)).ok();
                 }
                 Event::KeyDown {
                     keycode: Some(key),
                     repeat: false,
                     ..
                 } => {
-                    if let Some(ref midinote) = keymap.get(&key) {
-                        for osc in oscs.lock().unwrap().iter_mut() {
-                            let note = midinote.transpose(transpose);
-                            if !osc.is_playing() {
-                                println!(
-                                    "\tPlaying note {}, frequency {}",
-                                    note.note,
-                                    note.to_frequency()
-                                );
-                                osc.note_on(note.to_frequency());
-                                break;
-                            }
+                    let note = keymap
+                        .get(&key)
+                        .and_then(|n| n.checked_transpose(transpose));
+                    if let Some(note) = note {
+                        let event = NoteEvent::new(note, velocity);
+                        if commands.push(Command::NoteOn(event)).is_ok() {
+                            held_keys.insert(key);
+                            println!("\tPlaying note {}", describe_note(note, &tuning));
                         }
                     }
                 }
                 Event::KeyUp {
                     keycode: Some(key), ..
                 } => {
-                    if let Some(midinote) = keymap.get(&key) {
-                        for osc in oscs.lock().unwrap().iter_mut() {
-                            let note = midinote.transpose(transpose);
-                            if osc.is_playing()
-                                && (osc.get_frequency() - note.to_frequency()) < std::f64::EPSILON
-                            {
-                                println!(
-                                    "\tStopping note {}, frequency {}",
-                                    note.note,
-                                    note.to_frequency()
-                                );
-                                osc.note_off();
-                                break;
-                            }
-                        }
+                    let note = keymap
+                        .get(&key)
+                        .and_then(|n| n.checked_transpose(transpose));
+                    if let Some(note) = note {
+                        println!("\tStopping note {}", describe_note(note, &tuning));
+                        held_keys.remove(&key);
+                        commands.push(Command::NoteOff(note)).ok();
                     }
                 }
                 _ => {}
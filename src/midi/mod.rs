@@ -0,0 +1,651 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub mod smf;
+
+/// The octave number of middle C (MIDI note 60) in scientific pitch notation. Some software
+/// calls it C3 or C5 instead
+pub const DEFAULT_MIDDLE_C_OCTAVE: i32 = 4;
+
+/// The names of the notes in an octave, starting from C, using sharps
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The error returned when a note name can't be parsed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseNoteError {
+    message: String,
+}
+
+impl ParseNoteError {
+    fn new(message: &str, name: &str) -> Self {
+        ParseNoteError {
+            message: format!("{}: {}", message, name),
+        }
+    }
+}
+
+impl fmt::Display for ParseNoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseNoteError {}
+
+/// The highest MIDI note number, G9
+pub const MAX_NOTE: u8 = 127;
+
+/// A struct representing a MIDI note
+///
+/// The note number is always between 0 and `MAX_NOTE`.
+#[derive(Debug, Clone, Copy)]
+pub struct MidiNote {
+    note: u8,
+}
+
+impl MidiNote {
+    /// Creates a MidiNote from a note number
+    ///
+    /// # Panics
+    ///
+    /// Panics if the note number is above `MAX_NOTE`, use `try_new` for note numbers that have
+    /// not been checked
+    pub fn new(note: u8) -> Self {
+        Self::try_new(note).expect("MIDI note numbers go from 0 to 127")
+    }
+
+    /// Creates a MidiNote from a note number, or returns None if it is above `MAX_NOTE`
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// assert_eq!(MidiNote::try_new(127).map(MidiNote::number), Some(127));
+    /// assert_eq!(MidiNote::try_new(128), None);
+    /// ```
+    pub fn try_new(note: u8) -> Option<Self> {
+        if note <= MAX_NOTE {
+            Some(MidiNote { note })
+        } else {
+            None
+        }
+    }
+
+    /// The note number, from 0 to `MAX_NOTE`
+    pub fn number(self) -> u8 {
+        self.note
+    }
+
+    /// Converts this MIDI note into its associated frequency
+    pub fn to_frequency(self) -> f64 {
+        440.0 * (2.0_f64).powf((f64::from(self.note) - 69.0) / 12.0)
+    }
+
+    /// Converts this MIDI note into its associated frequency with the given
+    /// A4 base frequency (instead of the default 440 Hz
+    pub fn to_frequency_different_tuning(self, a4_tuning: f64) -> f64 {
+        a4_tuning * (2.0_f64).powf((f64::from(self.note) - 69.0) / 12.0)
+    }
+
+    /// Finds the note nearest to the given frequency, and how many cents the frequency is above
+    /// it (or below, if negative), or None if the frequency is outside of the MIDI range
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// let (note, cents) = MidiNote::from_frequency(445.0).unwrap();
+    /// assert_eq!(note, MidiNote::new(69));
+    /// assert!((cents - 19.56).abs() < 0.01);
+    /// assert_eq!(MidiNote::from_frequency(100_000.0), None);
+    /// ```
+    pub fn from_frequency(frequency: f64) -> Option<(Self, f64)> {
+        Self::from_frequency_different_tuning(frequency, 440.0)
+    }
+
+    /// Finds the note nearest to the given frequency, with the given A4 base frequency, and how
+    /// many cents the frequency is away from it
+    pub fn from_frequency_different_tuning(frequency: f64, a4_tuning: f64) -> Option<(Self, f64)> {
+        let semitones = 69.0 + 12.0 * (frequency / a4_tuning).log2();
+        let nearest = semitones.round();
+        if !(0.0..=f64::from(MAX_NOTE)).contains(&nearest) {
+            return None;
+        }
+        Some((MidiNote::new(nearest as u8), (semitones - nearest) * 100.0))
+    }
+
+    /// The octave this note is in, with middle C in the given octave
+    pub fn octave(self, middle_c_octave: i32) -> i32 {
+        i32::from(self.note) / 12 - 5 + middle_c_octave
+    }
+
+    /// The name of this note in scientific pitch notation (using sharps), with middle C in the
+    /// given octave
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// assert_eq!(MidiNote::new(60).name(4), "C4");
+    /// assert_eq!(MidiNote::new(60).name(3), "C3");
+    /// assert_eq!(MidiNote::new(10).to_string(), "A#-1");
+    /// ```
+    pub fn name(self, middle_c_octave: i32) -> String {
+        format!(
+            "{}{}",
+            NOTE_NAMES[usize::from(self.note % 12)],
+            self.octave(middle_c_octave)
+        )
+    }
+
+    /// Parses a note name in scientific pitch notation, with middle C in the given octave
+    ///
+    /// The letter can be upper or lower case, followed by an optional sharp (# or ♯) or flat
+    /// (b or ♭), then the octave number, which is digits with an optional leading minus sign.
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// assert_eq!(MidiNote::from_name("C3", 3), Ok(MidiNote::new(60)));
+    /// assert_eq!("F#3".parse(), Ok(MidiNote::new(54)));
+    /// assert_eq!("Bb-1".parse(), Ok(MidiNote::new(10)));
+    /// assert_eq!("Cb4".parse(), Ok(MidiNote::new(59)));
+    /// assert!("H2".parse::<MidiNote>().is_err());
+    /// assert!("G9".parse::<MidiNote>().is_ok());
+    /// assert!("G#9".parse::<MidiNote>().is_err());
+    /// assert!("C#b4".parse::<MidiNote>().is_err());
+    /// assert!("C+4".parse::<MidiNote>().is_err());
+    /// ```
+    pub fn from_name(name: &str, middle_c_octave: i32) -> Result<Self, ParseNoteError> {
+        let trimmed = name.trim();
+        let mut chars = trimmed.chars();
+        let semitone = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(ParseNoteError::new("invalid note letter", name)),
+        };
+
+        let after_letter = chars.as_str();
+        let (accidental, rest) = match chars.next() {
+            Some('#') | Some('♯') => (1, chars.as_str()),
+            Some('b') | Some('♭') => (-1, chars.as_str()),
+            _ => (0, after_letter),
+        };
+
+        // i32's parser would also take a leading plus sign
+        let digits = rest.strip_prefix('-').unwrap_or(rest);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseNoteError::new("invalid octave", name));
+        }
+        let octave: i32 = rest
+            .parse()
+            .map_err(|_| ParseNoteError::new("octave out of range", name))?;
+        let note = (octave - middle_c_octave + 5)
+            .checked_mul(12)
+            .and_then(|n| n.checked_add(semitone + accidental))
+            .ok_or_else(|| ParseNoteError::new("octave out of range", name))?;
+        if (0..=i32::from(MAX_NOTE)).contains(&note) {
+            Ok(MidiNote::new(note as u8))
+        } else {
+            Err(ParseNoteError::new("note out of range", name))
+        }
+    }
+
+    /// Returns this note transposed by the given number of semitones, or None if that would
+    /// take it out of the MIDI range
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// let note = MidiNote::new(64);
+    /// assert_eq!(note.checked_transpose( 4), Some(MidiNote::new(68)));
+    /// assert_eq!(note.checked_transpose(-4), Some(MidiNote::new(60)));
+    /// assert_eq!(note.checked_transpose(64), None);
+    /// assert_eq!(note.checked_transpose(-65), None);
+    /// ```
+    pub fn checked_transpose(self, semitones: i32) -> Option<Self> {
+        let note = i32::from(self.note).checked_add(semitones)?;
+        if (0..=i32::from(MAX_NOTE)).contains(&note) {
+            Some(MidiNote { note: note as u8 })
+        } else {
+            None
+        }
+    }
+
+    /// Returns this note transposed by the given number of semitones, stopping at the lowest
+    /// or highest MIDI note
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// let note = MidiNote::new(64);
+    /// assert_eq!(note.saturating_transpose(4), MidiNote::new(68));
+    /// assert_eq!(note.saturating_transpose(100), MidiNote::new(127));
+    /// assert_eq!(note.saturating_transpose(-100), MidiNote::new(0));
+    /// ```
+    pub fn saturating_transpose(self, semitones: i32) -> Self {
+        let note = i32::from(self.note).saturating_add(semitones);
+        MidiNote {
+            note: note.clamp(0, i32::from(MAX_NOTE)) as u8,
+        }
+    }
+}
+
+impl PartialEq for MidiNote {
+    fn eq(&self, other: &Self) -> bool {
+        self.note == other.note
+    }
+}
+
+impl fmt::Display for MidiNote {
+    /// Formats the note in scientific pitch notation, with middle C as C4
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name(DEFAULT_MIDDLE_C_OCTAVE))
+    }
+}
+
+impl FromStr for MidiNote {
+    type Err = ParseNoteError;
+
+    /// Parses a note in scientific pitch notation, with middle C as C4
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MidiNote::from_name(s, DEFAULT_MIDDLE_C_OCTAVE)
+    }
+}
+
+/// The highest MIDI velocity
+pub const MAX_VELOCITY: u8 = 127;
+
+/// A note being played, and how hard it was played
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteEvent {
+    pub note: MidiNote,
+    /// From 1 to 127, a velocity of 0 means the note is being released
+    pub velocity: u8,
+}
+
+impl NoteEvent {
+    pub fn new(note: MidiNote, velocity: u8) -> Self {
+        NoteEvent {
+            note,
+            velocity: velocity.min(MAX_VELOCITY),
+        }
+    }
+}
+
+/// The value of a centered pitch bend
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+/// Converts a 14 bit pitch bend value to the range -1.0 to just under 1.0, with 0.0 meaning no
+/// bend
+///
+/// ```rust
+/// # use sound_test::midi::{pitch_bend_to_normalized, PITCH_BEND_CENTER};
+/// assert_eq!(pitch_bend_to_normalized(PITCH_BEND_CENTER), 0.0);
+/// assert_eq!(pitch_bend_to_normalized(0), -1.0);
+/// assert!(pitch_bend_to_normalized(0x3FFF) > 0.999);
+/// ```
+pub fn pitch_bend_to_normalized(value: u16) -> f64 {
+    (f64::from(value.min(0x3FFF)) - f64::from(PITCH_BEND_CENTER)) / f64::from(PITCH_BEND_CENTER)
+}
+
+/// The controller number of the All Notes Off channel mode message
+pub const ALL_NOTES_OFF_CONTROLLER: u8 = 123;
+
+/// # MIDI Message
+///
+/// A MIDI 1.0 message, as sent over a MIDI cable or stored in a MIDI file
+///
+/// Channels are numbered from 0 to 15, and all other values are 7 bit except for the 14 bit
+/// pitch bend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: MidiNote,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: MidiNote,
+        velocity: u8,
+    },
+    PolyAftertouch {
+        channel: u8,
+        note: MidiNote,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelAftertouch {
+        channel: u8,
+        pressure: u8,
+    },
+    /// Pitch bend, from 0 to 0x3FFF with `PITCH_BEND_CENTER` meaning no bend
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// A system exclusive message, without the leading 0xF0 and trailing 0xF7
+    SysEx(Vec<u8>),
+    MtcQuarterFrame(u8),
+    /// Song position in MIDI beats (sixteenth notes)
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+/// The number of data bytes following a status byte, for messages with a fixed length
+fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    }
+}
+
+impl MidiMessage {
+    /// Builds a message from a status byte and its data bytes (unused data bytes are ignored)
+    ///
+    /// Returns None for status bytes that are not the start of a fixed length message, such as
+    /// 0xF0, and for data bytes. A note on with a velocity of zero is turned into a note off.
+    pub fn from_bytes(status: u8, data1: u8, data2: u8) -> Option<Self> {
+        let channel = status & 0x0F;
+        let message = match status {
+            0x80..=0x8F => MidiMessage::NoteOff {
+                channel,
+                note: MidiNote::new(data1 & 0x7F),
+                velocity: data2,
+            },
+            0x90..=0x9F if data2 == 0 => MidiMessage::NoteOff {
+                channel,
+                note: MidiNote::new(data1 & 0x7F),
+                velocity: 0,
+            },
+            0x90..=0x9F => MidiMessage::NoteOn {
+                channel,
+                note: MidiNote::new(data1 & 0x7F),
+                velocity: data2,
+            },
+            0xA0..=0xAF => MidiMessage::PolyAftertouch {
+                channel,
+                note: MidiNote::new(data1 & 0x7F),
+                pressure: data2,
+            },
+            0xB0..=0xBF => MidiMessage::ControlChange {
+                channel,
+                controller: data1,
+                value: data2,
+            },
+            0xC0..=0xCF => MidiMessage::ProgramChange {
+                channel,
+                program: data1,
+            },
+            0xD0..=0xDF => MidiMessage::ChannelAftertouch {
+                channel,
+                pressure: data1,
+            },
+            0xE0..=0xEF => MidiMessage::PitchBend {
+                channel,
+                value: u16::from(data1) | u16::from(data2) << 7,
+            },
+            0xF1 => MidiMessage::MtcQuarterFrame(data1),
+            0xF2 => MidiMessage::SongPosition(u16::from(data1) | u16::from(data2) << 7),
+            0xF3 => MidiMessage::SongSelect(data1),
+            0xF6 => MidiMessage::TuneRequest,
+            0xF8 => MidiMessage::TimingClock,
+            0xFA => MidiMessage::Start,
+            0xFB => MidiMessage::Continue,
+            0xFC => MidiMessage::Stop,
+            0xFE => MidiMessage::ActiveSensing,
+            0xFF => MidiMessage::Reset,
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    /// The status byte of this message
+    pub fn status(&self) -> u8 {
+        match *self {
+            MidiMessage::NoteOff { channel, .. } => 0x80 | channel & 0x0F,
+            MidiMessage::NoteOn { channel, .. } => 0x90 | channel & 0x0F,
+            MidiMessage::PolyAftertouch { channel, .. } => 0xA0 | channel & 0x0F,
+            MidiMessage::ControlChange { channel, .. } => 0xB0 | channel & 0x0F,
+            MidiMessage::ProgramChange { channel, .. } => 0xC0 | channel & 0x0F,
+            MidiMessage::ChannelAftertouch { channel, .. } => 0xD0 | channel & 0x0F,
+            MidiMessage::PitchBend { channel, .. } => 0xE0 | channel & 0x0F,
+            MidiMessage::SysEx(_) => 0xF0,
+            MidiMessage::MtcQuarterFrame(_) => 0xF1,
+            MidiMessage::SongPosition(_) => 0xF2,
+            MidiMessage::SongSelect(_) => 0xF3,
+            MidiMessage::TuneRequest => 0xF6,
+            MidiMessage::TimingClock => 0xF8,
+            MidiMessage::Start => 0xFA,
+            MidiMessage::Continue => 0xFB,
+            MidiMessage::Stop => 0xFC,
+            MidiMessage::ActiveSensing => 0xFE,
+            MidiMessage::Reset => 0xFF,
+        }
+    }
+
+    /// The channel of this message, if it is a channel message
+    pub fn channel(&self) -> Option<u8> {
+        if self.status() < 0xF0 {
+            Some(self.status() & 0x0F)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this is a system real time message, which can appear in the middle of other
+    /// messages
+    pub fn is_real_time(&self) -> bool {
+        self.status() >= 0xF8
+    }
+
+    /// Appends the data bytes of this message (everything after the status byte) to the buffer
+    fn encode_data(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            MidiMessage::NoteOff { note, velocity, .. }
+            | MidiMessage::NoteOn { note, velocity, .. } => {
+                bytes.push(note.number());
+                bytes.push(velocity & 0x7F);
+            }
+            MidiMessage::PolyAftertouch { note, pressure, .. } => {
+                bytes.push(note.number());
+                bytes.push(pressure & 0x7F);
+            }
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } => {
+                bytes.push(controller & 0x7F);
+                bytes.push(value & 0x7F);
+            }
+            MidiMessage::ProgramChange { program, .. } => bytes.push(program & 0x7F),
+            MidiMessage::ChannelAftertouch { pressure, .. } => bytes.push(pressure & 0x7F),
+            MidiMessage::PitchBend { value, .. } | MidiMessage::SongPosition(value) => {
+                bytes.push((value & 0x7F) as u8);
+                bytes.push((value >> 7 & 0x7F) as u8);
+            }
+            MidiMessage::SysEx(ref data) => {
+                bytes.extend(data.iter().map(|b| b & 0x7F));
+                bytes.push(0xF7);
+            }
+            MidiMessage::MtcQuarterFrame(value) | MidiMessage::SongSelect(value) => {
+                bytes.push(value & 0x7F)
+            }
+            _ => (),
+        }
+    }
+
+    /// Appends the bytes of this message to the buffer
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.status());
+        self.encode_data(bytes);
+    }
+
+    /// Returns the bytes of this message
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3);
+        self.encode(&mut bytes);
+        bytes
+    }
+}
+
+/// # MIDI Parser
+///
+/// Turns a stream of MIDI bytes into messages, one byte at a time
+///
+/// Handles running status (data bytes without a status byte reuse the last channel message
+/// status), and real time messages in the middle of other messages. A system exclusive message
+/// that is interrupted by any other status byte is dropped.
+///
+/// ```rust
+/// # use sound_test::midi::{MidiMessage, MidiNote, MidiParser};
+/// let mut parser = MidiParser::new();
+/// // A note on, a timing clock in the middle of a second note on sent with running status
+/// let bytes = [0x90, 60, 100, 64, 0xF8, 90];
+/// let messages: Vec<MidiMessage> = bytes.iter().filter_map(|b| parser.parse(*b)).collect();
+/// assert_eq!(
+///     messages,
+///     vec![
+///         MidiMessage::NoteOn { channel: 0, note: MidiNote::new(60), velocity: 100 },
+///         MidiMessage::TimingClock,
+///         MidiMessage::NoteOn { channel: 0, note: MidiNote::new(64), velocity: 90 },
+///     ]
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MidiParser {
+    /// The status of the message being received, kept after it is complete for running status
+    status: Option<u8>,
+    /// Data bytes received so far
+    data: [u8; 2],
+    /// Number of data bytes received so far
+    data_received: usize,
+    /// Data of the system exclusive message being received
+    sysex: Vec<u8>,
+    /// Whether a system exclusive message is being received
+    in_sysex: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets any partially received message and the running status
+    pub fn reset(&mut self) {
+        self.status = None;
+        self.data_received = 0;
+        self.sysex.clear();
+        self.in_sysex = false;
+    }
+
+    /// Parses the next byte of the stream, returning a message if the byte completes one
+    pub fn parse(&mut self, byte: u8) -> Option<MidiMessage> {
+        // Real time messages are a single byte and do not affect anything else
+        if byte >= 0xF8 {
+            return MidiMessage::from_bytes(byte, 0, 0);
+        }
+
+        if self.in_sysex {
+            if byte < 0x80 {
+                self.sysex.push(byte);
+                return None;
+            }
+            self.in_sysex = false;
+            if byte == 0xF7 {
+                let data = std::mem::take(&mut self.sysex);
+                return Some(MidiMessage::SysEx(data));
+            }
+            self.sysex.clear();
+        }
+
+        if byte >= 0x80 {
+            self.data_received = 0;
+            match byte {
+                0xF0 => {
+                    self.status = None;
+                    self.in_sysex = true;
+                    return None;
+                }
+                0xF1..=0xF7 => {
+                    // System common messages cancel running status
+                    self.status = None;
+                    return match data_length(byte) {
+                        0 => MidiMessage::from_bytes(byte, 0, 0),
+                        _ => {
+                            self.status = Some(byte);
+                            None
+                        }
+                    };
+                }
+                _ => {
+                    self.status = Some(byte);
+                    return None;
+                }
+            }
+        }
+
+        // A data byte
+        let status = self.status?;
+        self.data[self.data_received] = byte;
+        self.data_received += 1;
+        if self.data_received < data_length(status) {
+            return None;
+        }
+
+        self.data_received = 0;
+        if status >= 0xF0 {
+            // Only channel messages can use running status
+            self.status = None;
+        }
+        MidiMessage::from_bytes(status, self.data[0], self.data[1])
+    }
+}
+
+/// # MIDI Encoder
+///
+/// Turns messages into a stream of MIDI bytes, leaving out repeated channel message status
+/// bytes (running status) to save bandwidth
+#[derive(Clone, Debug, Default)]
+pub struct MidiEncoder {
+    /// The status of the last channel message sent
+    running_status: Option<u8>,
+}
+
+impl MidiEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the bytes of the given message to the buffer
+    pub fn encode(&mut self, message: &MidiMessage, bytes: &mut Vec<u8>) {
+        let status = message.status();
+        if message.is_real_time() {
+            bytes.push(status);
+            return;
+        }
+
+        if status >= 0xF0 {
+            self.running_status = None;
+            bytes.push(status);
+        } else if self.running_status != Some(status) {
+            self.running_status = Some(status);
+            bytes.push(status);
+        }
+        message.encode_data(bytes);
+    }
+}
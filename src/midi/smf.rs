@@ -0,0 +1,472 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind};
+
+use crate::midi::{MidiMessage, MidiNote};
+use crate::render::ScheduledNote;
+use crate::synth::Synth;
+use crate::voice::Voice;
+
+/// Tempo used until the file sets one, 120 beats per minute
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// How the ticks in a Standard MIDI File map to time
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    /// Ticks are a fraction of a quarter note, so their length depends on the tempo
+    TicksPerQuarterNote(u16),
+    /// Ticks are a fraction of an SMPTE frame, and do not depend on the tempo
+    Smpte {
+        /// Frames per second, 29 meaning 29.97 (drop frame)
+        frames_per_second: u8,
+        ticks_per_frame: u8,
+    },
+}
+
+/// The contents of an event in a track
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackEventKind {
+    /// A channel message (a note on with velocity zero is read as a note off)
+    Midi(MidiMessage),
+    /// A tempo change, in microseconds per quarter note
+    Tempo(u32),
+    /// The end of the track
+    EndOfTrack,
+    /// Any other meta event, with its type and data
+    Meta { meta_type: u8, data: Vec<u8> },
+    /// A system exclusive message, without the leading 0xF0 or 0xF7
+    SysEx(Vec<u8>),
+}
+
+/// An event in a track
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackEvent {
+    /// Ticks since the previous event in the track
+    pub delta: u32,
+    pub kind: TrackEventKind,
+}
+
+/// A track, a list of events
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Track {
+    pub events: Vec<TrackEvent>,
+}
+
+/// A note event at a time in seconds from the start of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimedNoteEvent {
+    /// Seconds from the start of the file
+    pub time: f64,
+    pub channel: u8,
+    pub note: MidiNote,
+    pub velocity: u8,
+    /// Whether this is a note on or a note off
+    pub on: bool,
+}
+
+/// # Standard MIDI File
+///
+/// A parsed format 0 or format 1 Standard MIDI File
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    /// 0 for a single track, 1 for multiple tracks played together
+    pub format: u16,
+    pub timing: Timing,
+    pub tracks: Vec<Track>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads bytes from a chunk, failing if they run out
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        ByteReader { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> std::io::Result<&'a [u8]> {
+        if self.position + count > self.bytes.len() {
+            return Err(invalid("unexpected end of data"));
+        }
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> std::io::Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a variable length quantity, 7 bits per byte with the top bit set on all but the
+    /// last byte, at most 4 bytes long
+    fn read_variable_length(&mut self) -> std::io::Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | u32::from(byte & 0x7F);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("variable length quantity is too long"))
+    }
+}
+
+impl MidiFile {
+    /// Reads and parses a Standard MIDI File
+    pub fn read(file_name: &str) -> std::io::Result<Self> {
+        let file = File::open(file_name)?;
+        let mut bytes = vec![];
+        BufReader::new(file).read_to_end(&mut bytes)?;
+        Self::parse(&bytes)
+    }
+
+    /// Parses the bytes of a Standard MIDI File
+    pub fn parse(bytes: &[u8]) -> std::io::Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+
+        if reader.read_bytes(4)? != b"MThd" {
+            return Err(invalid("not a Standard MIDI File"));
+        }
+        let header_length = reader.read_u32()? as usize;
+        if header_length < 6 {
+            return Err(invalid("header chunk is too short"));
+        }
+        let mut header = ByteReader::new(reader.read_bytes(header_length)?);
+        let format = header.read_u16()?;
+        let track_count = header.read_u16()?;
+        let division = header.read_u16()?;
+
+        if format > 1 {
+            return Err(invalid("only format 0 and 1 files are supported"));
+        }
+
+        let timing = if division & 0x8000 == 0 {
+            if division == 0 {
+                return Err(invalid("zero ticks per quarter note"));
+            }
+            Timing::TicksPerQuarterNote(division)
+        } else {
+            // The top byte is the negative frames per second, in two's complement
+            let frames_per_second = ((division >> 8) as u8 as i8).wrapping_neg() as u8;
+            let ticks_per_frame = (division & 0xFF) as u8;
+            if frames_per_second == 0 || ticks_per_frame == 0 {
+                return Err(invalid("invalid SMPTE timing"));
+            }
+            Timing::Smpte {
+                frames_per_second,
+                ticks_per_frame,
+            }
+        };
+
+        let mut tracks = Vec::with_capacity(track_count as usize);
+        while tracks.len() < track_count as usize && !reader.is_empty() {
+            let id = reader.read_bytes(4)?;
+            let length = reader.read_u32()? as usize;
+            let chunk = reader.read_bytes(length)?;
+            // Unknown chunks are allowed, and should be skipped
+            if id == b"MTrk" {
+                tracks.push(Self::parse_track(chunk)?);
+            }
+        }
+        if tracks.len() < track_count as usize {
+            return Err(invalid("missing tracks"));
+        }
+
+        Ok(MidiFile {
+            format,
+            timing,
+            tracks,
+        })
+    }
+
+    fn parse_track(bytes: &[u8]) -> std::io::Result<Track> {
+        let mut reader = ByteReader::new(bytes);
+        let mut events = vec![];
+        let mut running_status = None;
+
+        while !reader.is_empty() {
+            let delta = reader.read_variable_length()?;
+            let first = reader.read_u8()?;
+
+            let kind = match first {
+                0xFF => {
+                    running_status = None;
+                    let meta_type = reader.read_u8()?;
+                    let length = reader.read_variable_length()? as usize;
+                    let data = reader.read_bytes(length)?;
+                    match meta_type {
+                        0x2F => TrackEventKind::EndOfTrack,
+                        0x51 if length == 3 => TrackEventKind::Tempo(
+                            u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]),
+                        ),
+                        _ => TrackEventKind::Meta {
+                            meta_type,
+                            data: data.to_vec(),
+                        },
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    running_status = None;
+                    let length = reader.read_variable_length()? as usize;
+                    TrackEventKind::SysEx(reader.read_bytes(length)?.to_vec())
+                }
+                0xF1..=0xFE => return Err(invalid("unexpected system message in track")),
+                _ => {
+                    // Data bytes without a status byte reuse the last status
+                    let (status, first_data) = if first & 0x80 != 0 {
+                        (first, None)
+                    } else {
+                        let status =
+                            running_status.ok_or_else(|| invalid("running status not set"))?;
+                        (status, Some(first))
+                    };
+                    running_status = Some(status);
+
+                    let data1 = match first_data {
+                        Some(byte) => byte,
+                        None => reader.read_u8()?,
+                    };
+                    // Program change and channel aftertouch only have one data byte
+                    let data2 = match status & 0xF0 {
+                        0xC0 | 0xD0 => 0,
+                        _ => reader.read_u8()?,
+                    };
+                    let message = MidiMessage::from_bytes(status, data1, data2)
+                        .ok_or_else(|| invalid("invalid channel message"))?;
+                    TrackEventKind::Midi(message)
+                }
+            };
+
+            let end = kind == TrackEventKind::EndOfTrack;
+            events.push(TrackEvent { delta, kind });
+            if end {
+                break;
+            }
+        }
+
+        Ok(Track { events })
+    }
+
+    /// Returns every note on and note off in the file, from all tracks, in order, with the times
+    /// converted to seconds using the tempo changes in the file
+    pub fn note_events(&self) -> Vec<TimedNoteEvent> {
+        // Merge all of the tracks, keeping events at the same tick in track order
+        let mut events = vec![];
+        for track in self.tracks.iter() {
+            let mut tick: u64 = 0;
+            for event in track.events.iter() {
+                tick += u64::from(event.delta);
+                events.push((tick, &event.kind));
+            }
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut timed_events = vec![];
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_tick = 0;
+        let mut time = 0.0;
+        for (tick, kind) in events {
+            time += self.ticks_to_seconds(tick - last_tick, tempo);
+            last_tick = tick;
+
+            let (channel, note, velocity, on) = match *kind {
+                TrackEventKind::Tempo(new_tempo) => {
+                    tempo = new_tempo;
+                    continue;
+                }
+                TrackEventKind::Midi(MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                }) => (channel, note, velocity, true),
+                TrackEventKind::Midi(MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                }) => (channel, note, velocity, false),
+                _ => continue,
+            };
+            timed_events.push(TimedNoteEvent {
+                time,
+                channel,
+                note,
+                velocity,
+                on,
+            });
+        }
+
+        timed_events
+    }
+
+    /// Converts a number of ticks to seconds at the given tempo
+    fn ticks_to_seconds(&self, ticks: u64, tempo: u32) -> f64 {
+        match self.timing {
+            Timing::TicksPerQuarterNote(ticks_per_quarter_note) => {
+                ticks as f64 * f64::from(tempo) / 1_000_000.0 / f64::from(ticks_per_quarter_note)
+            }
+            Timing::Smpte {
+                frames_per_second,
+                ticks_per_frame,
+            } => {
+                let frames_per_second = if frames_per_second == 29 {
+                    29.97
+                } else {
+                    f64::from(frames_per_second)
+                };
+                ticks as f64 / (frames_per_second * f64::from(ticks_per_frame))
+            }
+        }
+    }
+}
+
+/// # MIDI Player
+///
+/// Plays the notes of a Standard MIDI File on a set of voices, either in real time by calling
+/// `advance` as time passes, or offline by turning them into notes for an `OfflineRenderer`
+///
+/// ```rust
+/// # use sound_test::midi::smf::{MidiFile, MidiPlayer};
+/// // A format 0 file with one track playing middle C for one quarter note
+/// let bytes = [
+///     b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96,
+///     b'M', b'T', b'r', b'k', 0, 0, 0, 11,
+///     0x00, 0x90, 60, 100, // note on
+///     0x60, 60, 0, // note off, using running status and velocity 0
+///     0x00, 0xFF, 0x2F, 0x00, // end of track
+/// ];
+/// let file = MidiFile::parse(&bytes).unwrap();
+/// let player = MidiPlayer::new(&file);
+///
+/// let notes = player.to_scheduled_notes();
+/// assert_eq!(notes.len(), 1);
+/// assert_eq!(notes[0].note.number(), 60);
+/// // A quarter note at the default 120 beats per minute
+/// assert!((notes[0].length - 0.5).abs() < 1e-9);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MidiPlayer {
+    /// The note events to play
+    events: Vec<TimedNoteEvent>,
+    /// Index of the next event to play
+    next_event: usize,
+    /// Seconds since the start of playback
+    time: f64,
+}
+
+impl MidiPlayer {
+    pub fn new(file: &MidiFile) -> Self {
+        MidiPlayer {
+            events: file.note_events(),
+            next_event: 0,
+            time: 0.0,
+        }
+    }
+
+    /// The time of the last event, in seconds
+    pub fn duration(&self) -> f64 {
+        self.events.last().map_or(0.0, |event| event.time)
+    }
+
+    /// The current playback position, in seconds
+    pub fn get_time(&self) -> f64 {
+        self.time
+    }
+
+    /// Whether every event has been played
+    pub fn is_finished(&self) -> bool {
+        self.next_event >= self.events.len()
+    }
+
+    /// Goes back to the start of the file
+    pub fn rewind(&mut self) {
+        self.next_event = 0;
+        self.time = 0.0;
+    }
+
+    /// Moves playback forward by the given number of seconds, playing every event that falls in
+    /// that time on the given synth
+    pub fn advance<V: Voice>(&mut self, seconds: f64, synth: &mut Synth<V>) {
+        for event in self.advance_events(seconds) {
+            if event.on {
+                synth.note_on(event.note, event.velocity);
+            } else {
+                synth.note_off(event.note);
+            }
+        }
+    }
+
+    /// Moves playback forward by the given number of seconds, returning every event that falls
+    /// in that time
+    pub fn advance_events(&mut self, seconds: f64) -> &[TimedNoteEvent] {
+        self.time += seconds;
+        let first = self.next_event;
+        while let Some(event) = self.events.get(self.next_event) {
+            if event.time > self.time {
+                break;
+            }
+            self.next_event += 1;
+        }
+        &self.events[first..self.next_event]
+    }
+
+    /// Pairs up the note ons and note offs into notes that can be rendered offline
+    ///
+    /// Notes that are never released are held until the end of the file.
+    pub fn to_scheduled_notes(&self) -> Vec<ScheduledNote> {
+        let mut notes = vec![];
+        let mut held: Vec<&TimedNoteEvent> = vec![];
+        for event in self.events.iter() {
+            if event.on {
+                held.push(event);
+            } else if let Some(index) = held
+                .iter()
+                .position(|on| on.channel == event.channel && on.note == event.note)
+            {
+                let on = held.remove(index);
+                notes.push(ScheduledNote::with_velocity(
+                    on.note,
+                    on.velocity,
+                    on.time,
+                    event.time - on.time,
+                ));
+            }
+        }
+        let end = self.duration();
+        for on in held {
+            notes.push(ScheduledNote::with_velocity(
+                on.note,
+                on.velocity,
+                on.time,
+                end - on.time,
+            ));
+        }
+
+        notes
+    }
+}
@@ -0,0 +1,119 @@
+use std::f64::consts::FRAC_PI_2;
+
+/// Works out the gain of each channel for a sound at the given pan position, from -1.0 (the
+/// first channel) to 1.0 (the last), writing them into `gains`
+///
+/// The channels are taken to be spread evenly in a line, and the sound is split between the two
+/// channels either side of it with a sine / cosine law, so the total power stays the same
+/// wherever it is panned. With two channels, a sound in the center is 3 dB down in each. With
+/// one channel the gain is always 1.0.
+///
+/// ```rust
+/// # use sound_test::mix::pan_gains;
+/// let mut gains = [0.0; 2];
+/// pan_gains(-1.0, &mut gains);
+/// assert_eq!(gains, [1.0, 0.0]);
+///
+/// pan_gains(0.0, &mut gains);
+/// assert!((gains[0] - gains[1]).abs() < 1e-12);
+/// assert!((gains[0] * gains[0] + gains[1] * gains[1] - 1.0).abs() < 1e-12);
+/// ```
+pub fn pan_gains(pan: f64, gains: &mut [f64]) {
+    for gain in gains.iter_mut() {
+        *gain = 0.0;
+    }
+    match gains.len() {
+        0 => {}
+        1 => gains[0] = 1.0,
+        channels => {
+            let position = (pan.clamp(-1.0, 1.0) + 1.0) * 0.5 * (channels - 1) as f64;
+            let left = (position.floor() as usize).min(channels - 2);
+            let fraction = position - left as f64;
+            gains[left] = (fraction * FRAC_PI_2).cos();
+            gains[left + 1] = (fraction * FRAC_PI_2).sin();
+        }
+    }
+}
+
+/// # Mix Bus
+///
+/// Sums mono sounds into one frame of N channels, panning each one with `pan_gains`
+///
+/// The width scales every pan position towards or away from the center before it is used, so
+/// 0.0 folds everything to the middle, 1.0 leaves the positions alone and anything larger
+/// pushes sounds further out (clamped at the edges).
+///
+/// ```rust
+/// # use sound_test::mix::MixBus;
+/// let mut bus = MixBus::new(2);
+/// bus.add(1.0, -1.0);
+/// bus.add(0.5, 1.0);
+/// assert_eq!(bus.frame(), &[1.0, 0.5]);
+///
+/// // With no width both sounds end up in the middle
+/// bus.clear();
+/// bus.set_width(0.0);
+/// bus.add(1.0, -1.0);
+/// assert!((bus.frame()[0] - bus.frame()[1]).abs() < 1e-12);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MixBus {
+    /// How far pan positions are spread from the center
+    width: f64,
+    /// The sum of everything added since the last clear
+    frame: Vec<f64>,
+    /// Scratch space for the gains of the sound being added
+    gains: Vec<f64>,
+}
+
+impl MixBus {
+    /// Creates a MixBus with the given number of channels (at least one)
+    pub fn new(channels: usize) -> Self {
+        let channels = channels.max(1);
+        MixBus {
+            width: 1.0,
+            frame: vec![0.0; channels],
+            gains: vec![0.0; channels],
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// Sets the stereo width, 0.0 is mono, 1.0 is normal
+    pub fn set_width(&mut self, width: f64) {
+        self.width = width.max(0.0);
+    }
+
+    pub fn get_width(&self) -> f64 {
+        self.width
+    }
+
+    /// Empties the bus, ready for the next frame
+    pub fn clear(&mut self) {
+        for x in self.frame.iter_mut() {
+            *x = 0.0;
+        }
+    }
+
+    /// Adds a mono sample to the frame at the given pan position (-1.0 to 1.0)
+    pub fn add(&mut self, sample: f64, pan: f64) {
+        if sample == 0.0 {
+            return;
+        }
+        pan_gains(pan * self.width, &mut self.gains);
+        for (x, gain) in self.frame.iter_mut().zip(self.gains.iter()) {
+            *x += sample * gain;
+        }
+    }
+
+    /// The frame so far, one sample per channel
+    pub fn frame(&self) -> &[f64] {
+        &self.frame
+    }
+
+    pub fn frame_mut(&mut self) -> &mut [f64] {
+        &mut self.frame
+    }
+}
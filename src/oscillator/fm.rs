@@ -0,0 +1,507 @@
+use std::f64::consts::PI;
+
+use crate::envelope::Envelope;
+use crate::oscillator::{semitones_to_ratio, Pitch};
+
+/// The number of operators in the preset algorithms
+pub const DEFAULT_OPERATORS: usize = 4;
+
+/// # FM Operator
+///
+/// A sine oscillator whose phase can be pushed forwards and backwards every sample, by other
+/// operators or by its own output, with its own envelope
+///
+/// The frequency of an operator is the frequency of the note times its ratio, so an operator
+/// stays in tune with the others whatever note is played.
+#[derive(Clone, Debug)]
+pub struct Operator {
+    /// The pitch of the operator, which is the note times the ratio
+    pitch: Pitch,
+    /// The frequency of the note being played, with pitch bend applied
+    frequency: f64,
+    /// The frequency of the operator compared to the note
+    ratio: f64,
+    /// How loud the operator is, which for a modulator is the peak phase change in radians
+    level: f64,
+    /// How much of its own output the operator feeds back into its phase
+    feedback: f64,
+    /// Position in the current period, from 0.0 to 1.0
+    phase: f64,
+    /// The last two outputs, averaged for feedback so it doesn't oscillate at nyquist
+    history: [f64; 2],
+    /// The envelope shaping each note
+    envelope: Envelope,
+}
+
+impl Operator {
+    pub fn new(sample_rate: u64) -> Self {
+        Operator {
+            pitch: Pitch::new(sample_rate),
+            frequency: 0.0,
+            ratio: 1.0,
+            level: 1.0,
+            feedback: 0.0,
+            phase: 0.0,
+            history: [0.0; 2],
+            envelope: Envelope::new(sample_rate, 0.0, 0.0, 1.0, 0.0),
+        }
+    }
+
+    /// Sets the frequency of the operator compared to the note, whole numbers giving
+    /// harmonic sounds and anything else giving bells and clangs
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio.max(0.0);
+        self.pitch.set_frequency(self.frequency * self.ratio);
+    }
+
+    pub fn get_ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Sets the fine tuning in cents (hundredths of a semitone), on top of the ratio
+    pub fn set_detune(&mut self, cents: f64) {
+        self.pitch.set_detune(cents);
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.pitch.get_detune()
+    }
+
+    /// Sets how loud the operator is. For a modulator this is the modulation index, the
+    /// largest phase change it makes in radians, so it can usefully go well above 1.0
+    pub fn set_level(&mut self, level: f64) {
+        self.level = level.max(0.0);
+    }
+
+    pub fn get_level(&self) -> f64 {
+        self.level
+    }
+
+    /// Sets the largest phase change in radians the operator makes to itself, turning a sine
+    /// towards a saw as it goes up to about 1.5, and into noise beyond that
+    pub fn set_feedback(&mut self, feedback: f64) {
+        self.feedback = feedback.max(0.0);
+    }
+
+    pub fn get_feedback(&self) -> f64 {
+        self.feedback
+    }
+
+    /// The frequency the operator is actually playing at
+    pub fn get_current_frequency(&self) -> f64 {
+        self.pitch.get_current_frequency()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.pitch.set_sample_rate(sample_rate);
+        self.envelope.set_sample_rate(sample_rate);
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.pitch.get_sample_rate()
+    }
+
+    /// Sets the frequency of the note, which the ratio and detune are applied to
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.pitch.set_frequency(frequency * self.ratio);
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Attaches the given envelope to this operator, replacing the current one
+    ///
+    /// By default an operator has an envelope that simply gates the output on and off.
+    pub fn set_envelope(&mut self, mut envelope: Envelope) {
+        envelope.set_sample_rate(self.get_sample_rate());
+        self.envelope = envelope;
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
+    }
+
+    /// Starts a note of the given frequency, which the ratio and detune are applied to
+    pub fn note_on(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.pitch.start(frequency * self.ratio);
+        // An operator still sounding keeps its phase and feedback history
+        if !self.is_playing() {
+            self.phase = 0.0;
+            self.history = [0.0; 2];
+        }
+        self.envelope.gate_on();
+    }
+
+    pub fn note_off(&mut self) {
+        self.envelope.gate_off();
+    }
+
+    /// Whether the operator is making any sound, including the release of a note
+    pub fn is_playing(&self) -> bool {
+        self.envelope.is_active()
+    }
+
+    /// Generates the next sample with the phase moved by the given number of radians
+    ///
+    /// ```rust
+    /// # use sound_test::oscillator::fm::Operator;
+    /// let mut op = Operator::new(44100);
+    /// op.note_on(441.0);
+    /// // Without modulation an operator is a plain sine wave, with modulation the same wave
+    /// // is read from somewhere else
+    /// let mut copy = op.clone();
+    /// assert_eq!(op.step(0.0), 0.0);
+    /// assert!((copy.step(std::f64::consts::FRAC_PI_2) - 1.0).abs() < 1e-12);
+    /// ```
+    pub fn step(&mut self, modulation: f64) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        let amplitude = self.envelope.step() * self.level;
+        let feedback = self.feedback * (self.history[0] + self.history[1]) / 2.0;
+        let sample = (2.0 * PI * self.phase + modulation + feedback).sin();
+        self.history = [sample, self.history[0]];
+
+        self.phase += self.pitch.step();
+        self.phase -= self.phase.floor();
+
+        sample * amplitude
+    }
+}
+
+/// How the operators of an `FmOscillator` are connected
+///
+/// Operators may only be modulated by operators that come after them, which keeps out loops
+/// (feedback is set on each operator instead) and lets every operator be worked out in one
+/// pass from the last to the first. The carriers are the operators that are heard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FmAlgorithm {
+    /// For each operator, the operators modulating it
+    modulators: Vec<Vec<usize>>,
+    /// The operators that are heard
+    carriers: Vec<usize>,
+}
+
+impl FmAlgorithm {
+    /// Creates an algorithm from the operators modulating each operator and the operators that
+    /// are heard, or returns `None` if any operator is modulated by itself or one before it, or
+    /// there are no carriers
+    ///
+    /// ```rust
+    /// # use sound_test::oscillator::fm::FmAlgorithm;
+    /// // 3 modulates 2, 2 and 1 both modulate 0, and 0 is heard
+    /// let algorithm = FmAlgorithm::new(vec![vec![1, 2], vec![], vec![3], vec![]], vec![0]);
+    /// assert!(algorithm.is_some());
+    ///
+    /// // Loops aren't allowed
+    /// assert_eq!(FmAlgorithm::new(vec![vec![1], vec![0]], vec![0]), None);
+    /// ```
+    pub fn new(modulators: Vec<Vec<usize>>, carriers: Vec<usize>) -> Option<Self> {
+        let operators = modulators.len();
+        let valid_modulators = modulators
+            .iter()
+            .enumerate()
+            .all(|(i, m)| m.iter().all(|&m| m > i && m < operators));
+        let valid_carriers = !carriers.is_empty() && carriers.iter().all(|&c| c < operators);
+        if !valid_modulators || !valid_carriers {
+            return None;
+        }
+        Some(FmAlgorithm {
+            modulators,
+            carriers,
+        })
+    }
+
+    /// Every operator modulating the one before it, with only the first heard. The brightest
+    /// and harshest algorithm
+    pub fn stack(operators: usize) -> Self {
+        let operators = operators.max(1);
+        let modulators = (0..operators)
+            .map(|i| {
+                if i + 1 < operators {
+                    vec![i + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        FmAlgorithm {
+            modulators,
+            carriers: vec![0],
+        }
+    }
+
+    /// Pairs of operators, the second of each modulating the first, with the first of each
+    /// heard. Good for layering two sounds, like the tine and body of an electric piano
+    pub fn pairs(operators: usize) -> Self {
+        let operators = operators.max(1);
+        let modulators = (0..operators)
+            .map(|i| {
+                if i % 2 == 0 && i + 1 < operators {
+                    vec![i + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect();
+        FmAlgorithm {
+            modulators,
+            carriers: (0..operators).step_by(2).collect(),
+        }
+    }
+
+    /// Every other operator modulating the first, which is the only one heard
+    pub fn branch(operators: usize) -> Self {
+        let operators = operators.max(1);
+        let mut modulators = vec![vec![]; operators];
+        modulators[0] = (1..operators).collect();
+        FmAlgorithm {
+            modulators,
+            carriers: vec![0],
+        }
+    }
+
+    /// Every operator heard with no modulation at all, for additive organ sounds
+    pub fn parallel(operators: usize) -> Self {
+        let operators = operators.max(1);
+        FmAlgorithm {
+            modulators: vec![vec![]; operators],
+            carriers: (0..operators).collect(),
+        }
+    }
+
+    /// The number of operators the algorithm connects
+    pub fn operators(&self) -> usize {
+        self.modulators.len()
+    }
+
+    /// The operators modulating the given operator
+    pub fn modulators(&self, operator: usize) -> &[usize] {
+        &self.modulators[operator]
+    }
+
+    /// The operators that are heard
+    pub fn carriers(&self) -> &[usize] {
+        &self.carriers
+    }
+}
+
+impl Default for FmAlgorithm {
+    fn default() -> Self {
+        FmAlgorithm::stack(DEFAULT_OPERATORS)
+    }
+}
+
+/// # FM Oscillator
+///
+/// A set of operators connected by an algorithm, where each operator moves the phase of the
+/// operators it modulates, to make DX style FM (strictly phase modulation) sounds
+///
+/// ```rust
+/// # use sound_test::oscillator::fm::{FmAlgorithm, FmOscillator};
+/// // A bell: a carrier modulated at a ratio that isn't a whole number
+/// let mut osc = FmOscillator::new(44100, FmAlgorithm::stack(2));
+/// osc.operator_mut(1).set_ratio(3.5);
+/// osc.operator_mut(1).set_level(2.0);
+/// osc.note_on(441.0);
+///
+/// let samples: Vec<f64> = (0..44100).map(|_| osc.step()).collect();
+/// assert!(samples.iter().all(|x| x.abs() <= 1.0));
+///
+/// // With the modulator turned down the carrier is a plain sine wave
+/// osc.operator_mut(1).set_level(0.0);
+/// let peak = (0..44100).map(|_| osc.step()).fold(0.0_f64, |p, x| p.max(x.abs()));
+/// assert!((peak - 1.0).abs() < 1e-3);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FmOscillator {
+    /// frequency generated by this oscillator
+    frequency: f64,
+    /// Sample rate of the audio stream
+    sample_rate: u64,
+    /// pitch bend, in semitones
+    pitch_bend: f64,
+    /// fine tuning, in cents
+    detune: f64,
+    /// The operators, in the order the algorithm refers to them
+    operators: Vec<Operator>,
+    /// How the operators are connected
+    algorithm: FmAlgorithm,
+    /// The last output of each operator
+    outputs: Vec<f64>,
+    /// Whether the key for the current note is held down
+    held: bool,
+}
+
+impl FmOscillator {
+    /// Creates an FM oscillator with as many operators as the algorithm uses, each playing at
+    /// the frequency of the note
+    pub fn new(sample_rate: u64, algorithm: FmAlgorithm) -> Self {
+        let operators = algorithm.operators();
+        FmOscillator {
+            frequency: 0.0,
+            sample_rate,
+            pitch_bend: 0.0,
+            detune: 0.0,
+            operators: vec![Operator::new(sample_rate); operators],
+            algorithm,
+            outputs: vec![0.0; operators],
+            held: false,
+        }
+    }
+
+    /// Changes how the operators are connected, adding or removing operators to match.
+    /// Operators added while a key is held start playing the note straight away
+    pub fn set_algorithm(&mut self, algorithm: FmAlgorithm) {
+        let existing = self.operators.len();
+        let frequency = self.get_current_frequency();
+        let mut operator = Operator::new(self.sample_rate);
+        operator.set_frequency(frequency);
+        self.operators.resize(algorithm.operators(), operator);
+        if self.held {
+            for operator in self.operators.iter_mut().skip(existing) {
+                operator.note_on(frequency);
+            }
+        }
+        self.outputs.resize(algorithm.operators(), 0.0);
+        self.algorithm = algorithm;
+    }
+
+    pub fn get_algorithm(&self) -> &FmAlgorithm {
+        &self.algorithm
+    }
+
+    pub fn operators(&self) -> &[Operator] {
+        &self.operators
+    }
+
+    pub fn operator(&self, operator: usize) -> &Operator {
+        &self.operators[operator]
+    }
+
+    pub fn operator_mut(&mut self, operator: usize) -> &mut Operator {
+        &mut self.operators[operator]
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_frequency();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Sets the pitch bend in semitones. The change is smoothed over a few milliseconds, and
+    /// the wave carries on from where it is
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch_bend = semitones;
+        self.cook_frequency();
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch_bend
+    }
+
+    /// Sets the fine tuning in cents (hundredths of a semitone) of every operator, smoothed
+    /// like pitch bend
+    pub fn set_detune(&mut self, cents: f64) {
+        self.detune = cents;
+        self.cook_frequency();
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.detune
+    }
+
+    /// The frequency of the note actually being played, with pitch bend and detune applied
+    pub fn get_current_frequency(&self) -> f64 {
+        self.frequency * semitones_to_ratio(self.pitch_bend + self.detune / 100.0)
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.sample_rate = sample_rate;
+        for operator in self.operators.iter_mut() {
+            operator.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    fn cook_frequency(&mut self) {
+        let frequency = self.get_current_frequency();
+        for operator in self.operators.iter_mut() {
+            operator.set_frequency(frequency);
+        }
+    }
+
+    pub fn note_on(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        let frequency = self.get_current_frequency();
+        for operator in self.operators.iter_mut() {
+            operator.note_on(frequency);
+        }
+        self.held = true;
+    }
+
+    pub fn note_off(&mut self) {
+        self.held = false;
+        for operator in self.operators.iter_mut() {
+            operator.note_off();
+        }
+    }
+
+    /// Whether any of the carriers are making any sound, including the release of a note that
+    /// has been let go
+    pub fn is_playing(&self) -> bool {
+        self.algorithm
+            .carriers()
+            .iter()
+            .any(|&c| self.operators[c].is_playing())
+    }
+
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// The level of the loudest carrier's envelope
+    pub fn get_amplitude(&self) -> f64 {
+        self.algorithm
+            .carriers()
+            .iter()
+            .map(|&c| self.operators[c].get_envelope().get_level())
+            .fold(0.0, f64::max)
+    }
+
+    pub fn step(&mut self) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        // Modulators always come after the operators they modulate, so working backwards
+        // every modulator's output is ready by the time it is needed
+        for i in (0..self.operators.len()).rev() {
+            let modulation: f64 = self
+                .algorithm
+                .modulators(i)
+                .iter()
+                .map(|&m| self.outputs[m])
+                .sum();
+            self.outputs[i] = self.operators[i].step(modulation);
+        }
+
+        let carriers = self.algorithm.carriers();
+        carriers.iter().map(|&c| self.outputs[c]).sum::<f64>() / carriers.len() as f64
+    }
+}
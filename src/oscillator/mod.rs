@@ -1,2 +1,149 @@
+pub mod fm;
+pub mod polyblep;
+pub mod pulse;
 pub mod sine;
+pub mod sync;
+pub mod unison;
 pub mod wavetable;
+
+/// How long it takes a change of pitch bend or detune to mostly take effect, in seconds
+pub const PITCH_SMOOTHING_TIME: f64 = 0.005;
+
+/// The ratio between the frequencies of two notes the given number of semitones apart
+///
+/// ```rust
+/// # use sound_test::oscillator::semitones_to_ratio;
+/// assert_eq!(semitones_to_ratio(12.0), 2.0);
+/// assert_eq!(semitones_to_ratio(-24.0), 0.25);
+/// ```
+pub fn semitones_to_ratio(semitones: f64) -> f64 {
+    2.0f64.powf(semitones / 12.0)
+}
+
+/// The coefficient of a one pole smoother that covers about 63% of a change in the given time
+pub(crate) fn smoothing_coefficient(time: f64, sample_rate: u64) -> f64 {
+    if time <= 0.0 {
+        return 1.0;
+    }
+    1.0 - (-1.0 / (time * sample_rate as f64)).exp()
+}
+
+/// # Pitch
+///
+/// The pitch of an oscillator: the frequency of the note, the pitch bend and detune on top of
+/// it, and how far through the wave to move every sample
+///
+/// Changes of frequency, pitch bend and detune are smoothed over `PITCH_SMOOTHING_TIME`, so the
+/// wave carries on from where it is without a click. A new note jumps straight to its pitch
+/// with `start` instead.
+///
+/// ```rust
+/// # use sound_test::oscillator::Pitch;
+/// let mut pitch = Pitch::new(44100);
+/// pitch.start(441.0);
+/// assert_eq!(pitch.step(), 0.01);
+///
+/// // A bend takes a few milliseconds to arrive
+/// pitch.set_pitch_bend(12.0);
+/// assert!(pitch.step() < 0.02);
+/// let delta = (0..44100).map(|_| pitch.step()).last().unwrap();
+/// assert!((delta - 0.02).abs() < 1e-12);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Pitch {
+    /// frequency of the note
+    frequency: f64,
+    /// Sample rate of the audio stream
+    sample_rate: u64,
+    /// pitch bend, in semitones
+    pitch_bend: f64,
+    /// fine tuning, in cents
+    detune: f64,
+    /// fraction of the period to move every sample
+    delta: f64,
+    /// the fraction of the period to move every sample once changes have been smoothed out
+    target_delta: f64,
+    /// how much of the way to the target delta to move every sample
+    smoothing: f64,
+}
+
+impl Pitch {
+    pub fn new(sample_rate: u64) -> Self {
+        Pitch {
+            sample_rate,
+            smoothing: smoothing_coefficient(PITCH_SMOOTHING_TIME, sample_rate),
+            ..Self::default()
+        }
+    }
+
+    /// Jumps straight to the given frequency, for the start of a note
+    pub fn start(&mut self, frequency: f64) {
+        self.set_frequency(frequency);
+        self.delta = self.target_delta;
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_frequency();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Sets the pitch bend in semitones
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch_bend = semitones;
+        self.cook_frequency();
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch_bend
+    }
+
+    /// Sets the fine tuning in cents (hundredths of a semitone)
+    pub fn set_detune(&mut self, cents: f64) {
+        self.detune = cents;
+        self.cook_frequency();
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.detune
+    }
+
+    /// The frequency being aimed for, with pitch bend and detune applied
+    pub fn get_current_frequency(&self) -> f64 {
+        self.frequency * semitones_to_ratio(self.pitch_bend + self.detune / 100.0)
+    }
+
+    /// The frequency being aimed for as a fraction of the sample rate
+    pub fn get_normalized_frequency(&self) -> f64 {
+        self.target_delta
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.sample_rate = sample_rate;
+        self.smoothing = smoothing_coefficient(PITCH_SMOOTHING_TIME, sample_rate);
+        self.cook_frequency();
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    fn cook_frequency(&mut self) {
+        self.target_delta = self.get_current_frequency() / self.sample_rate as f64;
+    }
+
+    /// Moves the smoothed pitch on by a sample, returning the fraction of the period to move
+    /// through the wave for this sample
+    pub fn step(&mut self) -> f64 {
+        if self.delta != self.target_delta {
+            self.delta += (self.target_delta - self.delta) * self.smoothing;
+            if (self.target_delta - self.delta).abs() < 1e-9 * self.target_delta.abs() {
+                self.delta = self.target_delta;
+            }
+        }
+        self.delta
+    }
+}
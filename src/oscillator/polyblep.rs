@@ -0,0 +1,234 @@
+use crate::envelope::Envelope;
+use crate::oscillator::Pitch;
+
+/// The narrowest pulse allowed, as a fraction of the period, either way from a square wave
+const MIN_PULSE_WIDTH: f64 = 0.01;
+/// How much the triangle's integrator leaks each period, which stops it drifting away from zero
+const TRIANGLE_LEAK: f64 = 0.05;
+
+/// The shapes a `PolyBlepOscillator` can generate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolyBlepWaveform {
+    /// A rising saw wave
+    Saw,
+    /// A pulse wave, high for the pulse width and low for the rest of the period
+    Square,
+    /// A triangle wave, integrated from a band limited square wave
+    Triangle,
+}
+
+/// The polynomial band limited step: the difference between an ideal band limited step and a
+/// naive step, to be added around each discontinuity
+///
+/// `t` is the phase (from 0.0 to 1.0) relative to the discontinuity and `dt` is the phase
+/// increment per sample.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// # PolyBLEP Oscillator
+///
+/// An oscillator that generates saw, pulse and triangle waves directly, smoothing each jump in
+/// the wave with a polynomial band limited step (PolyBLEP) so they barely alias
+///
+/// Unlike a `WaveTableOscillator` it needs no tables, has no interpolation error, and the pulse
+/// width can be changed freely. It plays notes the same way, with an envelope, pitch bend and
+/// detune.
+///
+/// ```rust
+/// # use sound_test::oscillator::polyblep::{PolyBlepOscillator, PolyBlepWaveform};
+/// let mut osc = PolyBlepOscillator::new(44100, PolyBlepWaveform::Square);
+/// osc.set_pulse_width(0.25);
+/// osc.note_on(441.0);
+///
+/// // A quarter of each 100 sample period is high
+/// let samples: Vec<f64> = (0..44100).map(|_| osc.step()).collect();
+/// let high = samples.iter().filter(|x| **x > 0.0).count();
+/// assert!((high as f64 / 44100.0 - 0.25).abs() < 0.01);
+/// assert!(samples.iter().all(|x| x.abs() <= 1.0));
+/// ```
+#[derive(Clone, Debug)]
+pub struct PolyBlepOscillator {
+    /// The pitch being played
+    pitch: Pitch,
+    /// The shape of the wave
+    waveform: PolyBlepWaveform,
+    /// The fraction of each period the pulse wave is high for
+    pulse_width: f64,
+    /// Position in the current period, from 0.0 to 1.0
+    phase: f64,
+    /// amount to move the phase this sample
+    delta: f64,
+    /// The state of the integrator making the triangle wave
+    integrator: f64,
+    /// Whether the key for the current note is held down
+    held: bool,
+    /// The envelope shaping each note
+    envelope: Envelope,
+}
+
+impl PolyBlepOscillator {
+    pub fn new(sample_rate: u64, waveform: PolyBlepWaveform) -> Self {
+        PolyBlepOscillator {
+            pitch: Pitch::new(sample_rate),
+            waveform,
+            pulse_width: 0.5,
+            phase: 0.0,
+            delta: 0.0,
+            integrator: -1.0,
+            held: false,
+            envelope: Envelope::new(sample_rate, 0.0, 0.0, 1.0, 0.0),
+        }
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.pitch.set_frequency(frequency);
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.pitch.get_frequency()
+    }
+
+    pub fn set_waveform(&mut self, waveform: PolyBlepWaveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn get_waveform(&self) -> PolyBlepWaveform {
+        self.waveform
+    }
+
+    /// Sets the fraction of each period the pulse wave is high for, 0.5 being a square wave.
+    /// Only used by `PolyBlepWaveform::Square`
+    pub fn set_pulse_width(&mut self, pulse_width: f64) {
+        self.pulse_width = pulse_width.clamp(MIN_PULSE_WIDTH, 1.0 - MIN_PULSE_WIDTH);
+    }
+
+    pub fn get_pulse_width(&self) -> f64 {
+        self.pulse_width
+    }
+
+    /// Sets the pitch bend in semitones, gliding to it the same way a `WaveTableOscillator` does
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch.set_pitch_bend(semitones);
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch.get_pitch_bend()
+    }
+
+    /// Sets the fine tuning in cents, which glides like pitch bend
+    pub fn set_detune(&mut self, cents: f64) {
+        self.pitch.set_detune(cents);
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.pitch.get_detune()
+    }
+
+    /// The frequency actually being played, with pitch bend and detune applied
+    pub fn get_current_frequency(&self) -> f64 {
+        self.pitch.get_current_frequency()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.pitch.set_sample_rate(sample_rate);
+        self.envelope.set_sample_rate(sample_rate);
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.pitch.get_sample_rate()
+    }
+
+    /// Attaches the given envelope to this oscillator, replacing the current one
+    ///
+    /// By default an oscillator has an envelope that simply gates the output on and off.
+    pub fn set_envelope(&mut self, mut envelope: Envelope) {
+        envelope.set_sample_rate(self.get_sample_rate());
+        self.envelope = envelope;
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
+    }
+
+    pub fn note_on(&mut self, frequency: f64) {
+        self.pitch.start(frequency);
+        // Carry on from the current phase if still sounding, restarting it would click
+        if !self.is_playing() {
+            self.phase = 0.0;
+            self.integrator = -1.0;
+        }
+        self.held = true;
+        self.envelope.gate_on();
+    }
+
+    pub fn note_off(&mut self) {
+        self.held = false;
+        self.envelope.gate_off();
+    }
+
+    /// Whether the oscillator is making any sound, including the release of a note that has
+    /// been let go
+    pub fn is_playing(&self) -> bool {
+        self.envelope.is_active()
+    }
+
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// A band limited pulse wave with the given pulse width at the current phase
+    fn pulse(&self, pulse_width: f64) -> f64 {
+        let t = self.phase;
+        let dt = self.delta;
+        let naive = if t < pulse_width { 1.0 } else { -1.0 };
+        let mut falling = t - pulse_width;
+        if falling < 0.0 {
+            falling += 1.0;
+        }
+        naive + poly_blep(t, dt) - poly_blep(falling, dt)
+    }
+
+    pub fn step(&mut self) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        let amplitude = self.envelope.step();
+        // Keep below Nyquist, where the step correction stops making sense
+        self.delta = self.pitch.step().min(0.5);
+        let dt = self.delta;
+        let sample = match self.waveform {
+            PolyBlepWaveform::Saw => 2.0 * self.phase - 1.0 - poly_blep(self.phase, dt),
+            PolyBlepWaveform::Square => self.pulse(self.pulse_width),
+            PolyBlepWaveform::Triangle => {
+                // A square wave rises or falls by 4 * dt each sample, going from -1.0 to 1.0
+                // over half a period
+                let square = self.pulse(0.5);
+                self.integrator =
+                    (self.integrator + 4.0 * dt * square) * (1.0 - TRIANGLE_LEAK * dt);
+                self.integrator
+            }
+        };
+
+        self.phase += self.delta;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        sample * amplitude
+    }
+}
@@ -0,0 +1,251 @@
+use crate::envelope::Envelope;
+use crate::oscillator::sine::SineOscillator;
+use crate::oscillator::wavetable::{BandLimitedWaveTable, BAND_LIMITED_SAW_WAVE_TABLE};
+use crate::oscillator::Pitch;
+
+/// The narrowest pulse allowed, as a fraction of the period, either way from a square wave
+const MIN_PULSE_WIDTH: f64 = 0.01;
+
+/// # Pulse Oscillator
+///
+/// A pulse wave with a pulse width that can be changed smoothly, even every sample, made by
+/// subtracting a band limited saw wave from a copy of itself shifted by the pulse width
+///
+/// Both saws come from the same band limited table, so the pulse is exactly as free of aliasing
+/// as the saw, and no tables have to be made for each pulse width. The pulse width can be swept
+/// by the built in LFO for classic pulse width modulation, or by any other signal through
+/// `step_with_modulation`.
+///
+/// ```rust
+/// # use sound_test::oscillator::pulse::PulseOscillator;
+/// let mut osc = PulseOscillator::new(44100);
+/// osc.set_pulse_width(0.25);
+/// osc.note_on(441.0);
+///
+/// // A quarter of each period is high, which leaves the average at 2 * 0.25 - 1
+/// let samples: Vec<f64> = (0..44100).map(|_| osc.step()).collect();
+/// let high = samples.iter().filter(|x| **x > 0.0).count();
+/// assert!((high as f64 / 44100.0 - 0.25).abs() < 0.02);
+/// let mean = samples.iter().sum::<f64>() / 44100.0;
+/// assert!((mean - (2.0 * 0.25 - 1.0)).abs() < 0.01);
+/// ```
+#[derive(Clone, Debug)]
+pub struct PulseOscillator {
+    /// The pitch being played
+    pitch: Pitch,
+    /// Current index into the saw table
+    index: f64,
+    /// the band limited saw tables
+    table: BandLimitedWaveTable,
+    /// the level of the band limited table being played at the current frequency
+    level: usize,
+    /// how much of the next level to mix in at the current frequency
+    crossfade: f64,
+    /// The height of the table's ramp compared to a naive saw going from -1.0 to 1.0
+    ramp_scale: f64,
+    /// The fraction of each period the pulse is high for, before modulation
+    pulse_width: f64,
+    /// The LFO modulating the pulse width
+    lfo: SineOscillator,
+    /// How far the LFO moves the pulse width
+    lfo_depth: f64,
+    /// Whether the key for the current note is held down
+    held: bool,
+    /// The envelope shaping each note
+    envelope: Envelope,
+}
+
+impl PulseOscillator {
+    /// Creates a pulse oscillator using the standard band limited saw table
+    pub fn new(sample_rate: u64) -> Self {
+        Self::from_saw_table(sample_rate, BAND_LIMITED_SAW_WAVE_TABLE.clone())
+    }
+
+    /// Creates a pulse oscillator from the given band limited saw table, which should contain
+    /// a saw wave rising over the period
+    pub fn from_saw_table(sample_rate: u64, table: BandLimitedWaveTable) -> Self {
+        // Compare the fundamental of the table with that of a naive saw, 2 / pi
+        let level = table.level(0);
+        let size = table.len();
+        let fundamental: f64 = (0..size)
+            .map(|i| level[i] * (2.0 * std::f64::consts::PI * i as f64 / size as f64).sin())
+            .sum::<f64>()
+            * 2.0
+            / size as f64;
+        let ramp_scale = fundamental * std::f64::consts::FRAC_PI_2;
+
+        PulseOscillator {
+            pitch: Pitch::new(sample_rate),
+            index: 0.0,
+            table,
+            level: 0,
+            crossfade: 0.0,
+            ramp_scale,
+            pulse_width: 0.5,
+            lfo: SineOscillator::new(0.0, sample_rate),
+            lfo_depth: 0.0,
+            held: false,
+            envelope: Envelope::new(sample_rate, 0.0, 0.0, 1.0, 0.0),
+        }
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.pitch.set_frequency(frequency);
+        self.select_level();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.pitch.get_frequency()
+    }
+
+    /// Sets the fraction of each period the pulse is high for, 0.5 being a square wave
+    pub fn set_pulse_width(&mut self, pulse_width: f64) {
+        self.pulse_width = pulse_width.clamp(MIN_PULSE_WIDTH, 1.0 - MIN_PULSE_WIDTH);
+    }
+
+    pub fn get_pulse_width(&self) -> f64 {
+        self.pulse_width
+    }
+
+    /// Sets the rate of the LFO modulating the pulse width, in Hz
+    pub fn set_pwm_rate(&mut self, rate: f64) {
+        self.lfo.set_frequency(rate);
+    }
+
+    pub fn get_pwm_rate(&self) -> f64 {
+        self.lfo.get_frequency()
+    }
+
+    /// Sets how far the LFO moves the pulse width either side of its setting, 0.0 turns pulse
+    /// width modulation off
+    pub fn set_pwm_depth(&mut self, depth: f64) {
+        self.lfo_depth = depth.abs();
+    }
+
+    pub fn get_pwm_depth(&self) -> f64 {
+        self.lfo_depth
+    }
+
+    /// Sets the pitch bend in semitones, which both saws follow together
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch.set_pitch_bend(semitones);
+        self.select_level();
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch.get_pitch_bend()
+    }
+
+    /// Sets the fine tuning in cents
+    pub fn set_detune(&mut self, cents: f64) {
+        self.pitch.set_detune(cents);
+        self.select_level();
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.pitch.get_detune()
+    }
+
+    /// The frequency being played, including pitch bend and detune
+    pub fn get_current_frequency(&self) -> f64 {
+        self.pitch.get_current_frequency()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.pitch.set_sample_rate(sample_rate);
+        self.envelope.set_sample_rate(sample_rate);
+        self.lfo.set_sample_rate(sample_rate);
+        self.select_level();
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.pitch.get_sample_rate()
+    }
+
+    /// Picks the level of the saw table both saws are read from
+    fn select_level(&mut self) {
+        let (level, crossfade) = self.table.select(self.pitch.get_normalized_frequency());
+        self.level = level;
+        self.crossfade = crossfade;
+    }
+
+    /// Attaches the given envelope to this oscillator, replacing the current one
+    ///
+    /// By default an oscillator has an envelope that simply gates the output on and off.
+    pub fn set_envelope(&mut self, mut envelope: Envelope) {
+        envelope.set_sample_rate(self.get_sample_rate());
+        self.envelope = envelope;
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
+    }
+
+    pub fn note_on(&mut self, frequency: f64) {
+        self.pitch.start(frequency);
+        self.select_level();
+        // A pulse still sounding keeps its phase
+        if !self.is_playing() {
+            self.index = 0.0;
+        }
+        self.held = true;
+        self.envelope.gate_on();
+    }
+
+    pub fn note_off(&mut self) {
+        self.held = false;
+        self.envelope.gate_off();
+    }
+
+    /// Whether the oscillator is making any sound, including the release of a note that has
+    /// been let go
+    pub fn is_playing(&self) -> bool {
+        self.envelope.is_active()
+    }
+
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    pub fn step(&mut self) -> f64 {
+        self.step_with_modulation(0.0)
+    }
+
+    /// Generates the next sample with the given amount added to the pulse width, on top of the
+    /// LFO, so the pulse width can be modulated by any signal at audio rate
+    pub fn step_with_modulation(&mut self, modulation: f64) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        let mut pulse_width = self.pulse_width + modulation;
+        if self.lfo_depth > 0.0 {
+            pulse_width += self.lfo_depth * self.lfo.step().0;
+        }
+        let pulse_width = pulse_width.clamp(MIN_PULSE_WIDTH, 1.0 - MIN_PULSE_WIDTH);
+
+        let amplitude = self.envelope.step();
+        let size = self.table.len() as f64;
+        let mut shifted = self.index + pulse_width * size;
+        if shifted >= size {
+            shifted -= size;
+        }
+        // The two saws cancel apart from a step up and a step down, pulse width apart. Their
+        // difference has no DC, so the offset puts the flat parts back at -1.0 and 1.0
+        let difference = self.table.sample(self.level, self.crossfade, self.index)
+            - self.table.sample(self.level, self.crossfade, shifted);
+        let sample = difference / self.ramp_scale + 2.0 * pulse_width - 1.0;
+
+        self.index += self.pitch.step() * size;
+        if self.index >= size {
+            self.index -= size;
+        }
+
+        sample * amplitude
+    }
+}
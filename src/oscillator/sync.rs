@@ -0,0 +1,157 @@
+use crate::envelope::Envelope;
+use crate::oscillator::wavetable::{
+    BandLimitedWaveTable, SyncMode, WaveTableOscillator, SINE_WAVE_TABLE,
+};
+
+/// # Synced Oscillator
+///
+/// A pair of oscillators, where a master oscillator playing the note keeps resetting (or
+/// reversing) a slave oscillator playing faster than it
+///
+/// Only the slave is heard. The master forces it to repeat at the frequency of the note, so the
+/// ratio between the two changes the timbre rather than the pitch, and sweeping it gives the
+/// classic sync lead sound.
+///
+/// ```rust
+/// # use sound_test::oscillator::sync::SyncedOscillator;
+/// # use sound_test::oscillator::wavetable::{SyncMode, BAND_LIMITED_SAW_WAVE_TABLE};
+/// let mut osc =
+///     SyncedOscillator::new(44100, BAND_LIMITED_SAW_WAVE_TABLE.clone(), SyncMode::Hard);
+/// osc.set_ratio(2.7);
+/// osc.note_on(441.0);
+///
+/// // Whatever the ratio, the wave repeats every 100 samples once it has got going
+/// let samples: Vec<f64> = (0..4410).map(|_| osc.step()).collect();
+/// assert!((200..4410).all(|i| (samples[i] - samples[i - 100]).abs() < 0.05));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SyncedOscillator {
+    /// The oscillator whose cycles sync the slave, which is never heard
+    master: WaveTableOscillator,
+    /// The oscillator that is heard
+    slave: WaveTableOscillator,
+    /// How the slave follows the master
+    mode: SyncMode,
+    /// The frequency of the slave compared to the master
+    ratio: f64,
+}
+
+impl SyncedOscillator {
+    /// Creates a synced oscillator where the slave plays the given table
+    pub fn new(sample_rate: u64, table: BandLimitedWaveTable, mode: SyncMode) -> Self {
+        SyncedOscillator {
+            master: WaveTableOscillator::new(sample_rate, SINE_WAVE_TABLE.clone()),
+            slave: WaveTableOscillator::new_band_limited(sample_rate, table),
+            mode,
+            ratio: 1.0,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: SyncMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    /// Sets the frequency of the slave compared to the master. Changes are smoothed like
+    /// pitch bend, so the ratio can be swept
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio.max(0.0);
+        self.slave
+            .set_frequency(self.master.get_frequency() * self.ratio);
+    }
+
+    pub fn get_ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.master.set_frequency(frequency);
+        self.slave.set_frequency(frequency * self.ratio);
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.master.get_frequency()
+    }
+
+    /// Sets the pitch bend in semitones of both oscillators
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.master.set_pitch_bend(semitones);
+        self.slave.set_pitch_bend(semitones);
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.master.get_pitch_bend()
+    }
+
+    /// Sets the fine tuning in cents of both oscillators
+    pub fn set_detune(&mut self, cents: f64) {
+        self.master.set_detune(cents);
+        self.slave.set_detune(cents);
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.master.get_detune()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.master.set_sample_rate(sample_rate);
+        self.slave.set_sample_rate(sample_rate);
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.slave.get_sample_rate()
+    }
+
+    /// Attaches the given envelope to the slave, replacing the current one
+    pub fn set_envelope(&mut self, envelope: Envelope) {
+        self.slave.set_envelope(envelope);
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        self.slave.get_envelope()
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        self.slave.get_envelope_mut()
+    }
+
+    pub fn note_on(&mut self, frequency: f64) {
+        // The master keeps running through the release of the slave, so it has to be stopped
+        // by hand for both to start the note together
+        if !self.slave.is_playing() {
+            self.master.get_envelope_mut().reset();
+        }
+        self.master.note_on(frequency);
+        self.slave.note_on(frequency * self.ratio);
+    }
+
+    pub fn note_off(&mut self) {
+        self.slave.note_off();
+    }
+
+    /// Whether the oscillator is making any sound, including the release of a note that has
+    /// been let go
+    pub fn is_playing(&self) -> bool {
+        self.slave.is_playing()
+    }
+
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.slave.is_held()
+    }
+
+    pub fn step(&mut self) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        self.master.step();
+        if let Some(offset) = self.master.last_wrap() {
+            self.slave.sync(self.mode, offset);
+        }
+        self.slave.step()
+    }
+}
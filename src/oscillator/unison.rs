@@ -0,0 +1,352 @@
+use crate::envelope::Envelope;
+use crate::mix::MixBus;
+use crate::oscillator::wavetable::{BandLimitedWaveTable, WaveTableOscillator};
+use crate::random::Random;
+
+/// The most copies a `UnisonOscillator` can stack
+pub const MAX_UNISON_VOICES: usize = 16;
+
+/// How the copies of a `UnisonOscillator` are spread out between the lowest and highest detune
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DetuneCurve {
+    /// Evenly spread
+    Linear,
+    /// Bunched up towards the middle by the given power, leaving the outer copies further out
+    /// on their own, like the classic supersaw. 1.0 is the same as linear
+    Power(f64),
+}
+
+impl DetuneCurve {
+    /// Maps a position between -1.0 and 1.0 to the fraction of the detune to apply there
+    ///
+    /// ```rust
+    /// # use sound_test::oscillator::unison::DetuneCurve;
+    /// assert_eq!(DetuneCurve::Linear.apply(-0.5), -0.5);
+    /// assert_eq!(DetuneCurve::Power(2.0).apply(-0.5), -0.25);
+    /// assert_eq!(DetuneCurve::Power(2.0).apply(1.0), 1.0);
+    /// ```
+    pub fn apply(self, position: f64) -> f64 {
+        match self {
+            DetuneCurve::Linear => position,
+            DetuneCurve::Power(power) => position.signum() * position.abs().powf(power.max(0.0)),
+        }
+    }
+}
+
+/// # Unison Oscillator
+///
+/// Up to 16 copies of a wave table oscillator, each detuned and panned a little differently,
+/// playing the same note, for thick pads and supersaw leads
+///
+/// Each note starts the copies at random points in the wave so they don't all line up into
+/// one loud spike, and the output is scaled down by the square root of the number of copies,
+/// which keeps the loudness about the same however many there are.
+///
+/// ```rust
+/// # use sound_test::oscillator::unison::UnisonOscillator;
+/// # use sound_test::oscillator::wavetable::BAND_LIMITED_SAW_WAVE_TABLE;
+/// let mut osc = UnisonOscillator::new(44100, BAND_LIMITED_SAW_WAVE_TABLE.clone());
+/// osc.set_voices(7);
+/// osc.set_spread_detune(25.0);
+/// osc.note_on(220.0);
+///
+/// let samples: Vec<f64> = (0..44100).map(|_| osc.step()).collect();
+/// let power = samples.iter().map(|x| x * x).sum::<f64>() / 44100.0;
+///
+/// // About as loud as a single band limited saw, whose power is about 0.24
+/// assert!(power > 0.2 && power < 0.5);
+/// ```
+#[derive(Clone, Debug)]
+pub struct UnisonOscillator {
+    /// frequency generated by this oscillator
+    frequency: f64,
+    /// Sample rate of the audio stream
+    sample_rate: u64,
+    /// pitch bend, in semitones
+    pitch_bend: f64,
+    /// fine tuning of every copy, in cents
+    detune: f64,
+    /// The copies, only the first `voices` of which are played
+    oscillators: Vec<WaveTableOscillator>,
+    /// How many copies are played
+    voices: usize,
+    /// How far the outermost copies are detuned, in cents either way
+    spread_detune: f64,
+    /// How the copies are spread out between the outermost ones
+    detune_curve: DetuneCurve,
+    /// How far the outermost copies are panned, from 0.0 (all in the middle) to 1.0
+    stereo_spread: f64,
+    /// Whether each note starts the copies at random points in the wave
+    random_phase: bool,
+    /// The source of the random starting points
+    random: Random,
+    /// Whether the key for the current note is held down
+    held: bool,
+    /// The envelope shaping each note
+    envelope: Envelope,
+}
+
+impl UnisonOscillator {
+    /// Creates a unison oscillator playing the given table, with one copy to start with
+    pub fn new(sample_rate: u64, table: BandLimitedWaveTable) -> Self {
+        UnisonOscillator {
+            frequency: 0.0,
+            sample_rate,
+            pitch_bend: 0.0,
+            detune: 0.0,
+            oscillators: vec![
+                WaveTableOscillator::new_band_limited(sample_rate, table);
+                MAX_UNISON_VOICES
+            ],
+            voices: 1,
+            spread_detune: 0.0,
+            detune_curve: DetuneCurve::Linear,
+            stereo_spread: 0.0,
+            random_phase: true,
+            random: Random::default(),
+            held: false,
+            envelope: Envelope::new(sample_rate, 0.0, 0.0, 1.0, 0.0),
+        }
+    }
+
+    /// Sets how many copies are played, from 1 to `MAX_UNISON_VOICES`
+    pub fn set_voices(&mut self, voices: usize) {
+        let voices = voices.clamp(1, MAX_UNISON_VOICES);
+        let existing = self.voices;
+        self.voices = voices;
+        self.cook_frequency();
+        // Copies joining part way through a note start with everything else, already detuned
+        if self.is_playing() {
+            for i in existing..voices {
+                self.oscillators[i].get_envelope_mut().reset();
+                self.oscillators[i].note_on(self.frequency);
+                if self.random_phase {
+                    self.oscillators[i].set_phase(self.random.next_f64());
+                }
+            }
+        }
+    }
+
+    pub fn get_voices(&self) -> usize {
+        self.voices
+    }
+
+    /// Sets how far the outermost copies are detuned, in cents either way
+    pub fn set_spread_detune(&mut self, cents: f64) {
+        self.spread_detune = cents.abs();
+        self.cook_frequency();
+    }
+
+    pub fn get_spread_detune(&self) -> f64 {
+        self.spread_detune
+    }
+
+    pub fn set_detune_curve(&mut self, detune_curve: DetuneCurve) {
+        self.detune_curve = detune_curve;
+        self.cook_frequency();
+    }
+
+    pub fn get_detune_curve(&self) -> DetuneCurve {
+        self.detune_curve
+    }
+
+    /// Sets how far the outermost copies are panned, from 0.0 (all in the middle) to 1.0 (hard
+    /// left and right). Only heard through `step_into`, which is what `Synth` plays voices with
+    pub fn set_stereo_spread(&mut self, spread: f64) {
+        self.stereo_spread = spread.clamp(0.0, 1.0);
+    }
+
+    pub fn get_stereo_spread(&self) -> f64 {
+        self.stereo_spread
+    }
+
+    /// Sets whether each note starts the copies at random points in the wave, rather than all
+    /// at the beginning
+    pub fn set_random_phase(&mut self, random_phase: bool) {
+        self.random_phase = random_phase;
+    }
+
+    pub fn get_random_phase(&self) -> bool {
+        self.random_phase
+    }
+
+    /// Restarts the random starting points from the given seed, so notes can be repeated
+    /// exactly
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random = Random::new(seed);
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_frequency();
+    }
+
+    pub fn get_frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Sets the pitch bend in semitones of every copy
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch_bend = semitones;
+        for oscillator in self.oscillators.iter_mut() {
+            oscillator.set_pitch_bend(semitones);
+        }
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch_bend
+    }
+
+    /// Sets the fine tuning in cents of every copy, on top of the spread detune
+    pub fn set_detune(&mut self, cents: f64) {
+        self.detune = cents;
+        self.cook_frequency();
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.detune
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u64) {
+        self.sample_rate = sample_rate;
+        self.envelope.set_sample_rate(sample_rate);
+        for oscillator in self.oscillators.iter_mut() {
+            oscillator.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    /// Where the given copy sits between the outermost ones, from -1.0 to 1.0
+    fn position(&self, voice: usize) -> f64 {
+        if self.voices == 1 {
+            return 0.0;
+        }
+        2.0 * voice as f64 / (self.voices - 1) as f64 - 1.0
+    }
+
+    fn cook_frequency(&mut self) {
+        for i in 0..self.voices {
+            let detune =
+                self.detune + self.spread_detune * self.detune_curve.apply(self.position(i));
+            self.oscillators[i].set_detune(detune);
+            self.oscillators[i].set_frequency(self.frequency);
+        }
+    }
+
+    /// Attaches the given envelope to this oscillator, replacing the current one
+    ///
+    /// By default an oscillator has an envelope that simply gates the output on and off.
+    pub fn set_envelope(&mut self, mut envelope: Envelope) {
+        envelope.set_sample_rate(self.sample_rate);
+        self.envelope = envelope;
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
+    }
+
+    pub fn note_on(&mut self, frequency: f64) {
+        self.frequency = frequency;
+        self.cook_frequency();
+        // The copies keep running through the release, so they have to be stopped by hand to
+        // start again from new points in the wave
+        let restart = !self.is_playing();
+        for i in 0..self.voices {
+            if restart {
+                self.oscillators[i].get_envelope_mut().reset();
+            }
+            self.oscillators[i].note_on(frequency);
+            if restart && self.random_phase {
+                self.oscillators[i].set_phase(self.random.next_f64());
+            }
+        }
+        self.held = true;
+        self.envelope.gate_on();
+    }
+
+    pub fn note_off(&mut self) {
+        self.held = false;
+        self.envelope.gate_off();
+    }
+
+    /// Whether the oscillator is making any sound, including the release of a note that has
+    /// been let go
+    pub fn is_playing(&self) -> bool {
+        self.envelope.is_active()
+    }
+
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// How much every copy is scaled by, so that the loudness doesn't change with the number
+    /// of copies. Copies at different frequencies add up in power rather than in level
+    fn gain(&self) -> f64 {
+        1.0 / (self.voices as f64).sqrt()
+    }
+
+    /// Generates the next sample of every copy mixed together in mono
+    pub fn step(&mut self) -> f64 {
+        if !self.is_playing() {
+            return 0.0;
+        }
+
+        let amplitude = self.envelope.step() * self.gain();
+        let sum: f64 = self.oscillators[..self.voices]
+            .iter_mut()
+            .map(WaveTableOscillator::step)
+            .sum();
+        sum * amplitude
+    }
+
+    /// Generates the next sample of every copy and adds it to the bus, each copy panned by the
+    /// stereo spread either side of the given pan position
+    ///
+    /// ```rust
+    /// # use sound_test::mix::MixBus;
+    /// # use sound_test::oscillator::unison::UnisonOscillator;
+    /// # use sound_test::oscillator::wavetable::BAND_LIMITED_SAW_WAVE_TABLE;
+    /// let mut osc = UnisonOscillator::new(44100, BAND_LIMITED_SAW_WAVE_TABLE.clone());
+    /// osc.set_voices(8);
+    /// osc.set_spread_detune(30.0);
+    /// osc.set_stereo_spread(1.0);
+    /// osc.note_on(220.0);
+    ///
+    /// // The two sides are different, but each is as loud as the other
+    /// let mut bus = MixBus::new(2);
+    /// let (mut left, mut right, mut difference) = (0.0, 0.0, 0.0);
+    /// for _ in 0..44100 {
+    ///     bus.clear();
+    ///     osc.step_into(&mut bus, 0.0);
+    ///     let frame = bus.frame();
+    ///     left += frame[0] * frame[0];
+    ///     right += frame[1] * frame[1];
+    ///     difference += (frame[0] - frame[1]).powi(2);
+    /// }
+    /// assert!((left / right - 1.0).abs() < 0.2);
+    /// assert!(difference > 0.1 * left);
+    /// ```
+    pub fn step_into(&mut self, bus: &mut MixBus, pan: f64) {
+        if !self.is_playing() {
+            return;
+        }
+
+        let amplitude = self.envelope.step() * self.gain();
+        for i in 0..self.voices {
+            let position = self.position(i);
+            let sample = self.oscillators[i].step() * amplitude;
+            bus.add(
+                sample,
+                (pan + self.stereo_spread * position).clamp(-1.0, 1.0),
+            );
+        }
+    }
+}
@@ -1,10 +1,17 @@
+use std::cmp::max;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::LineWriter;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
+
+use crate::envelope::Envelope;
+use crate::oscillator::Pitch;
+
 const DEFAULT_TABLE_SIZE: usize = 256;
+const BAND_LIMITED_TABLE_SIZE: usize = 2048;
 
 lazy_static! {
     pub static ref SINE_WAVE_TABLE: WaveTable = {
@@ -58,6 +65,31 @@ lazy_static! {
             table: array.to_vec(),
         }
     };
+    pub static ref BAND_LIMITED_SAW_WAVE_TABLE: BandLimitedWaveTable =
+        BandLimitedWaveTable::from_harmonics(BAND_LIMITED_TABLE_SIZE, |k| {
+            // Saw wave: all harmonics, alternating sign, falling off with 1/k
+            let sign = if k % 2 == 0 { -1.0 } else { 1.0 };
+            sign / k as f64
+        });
+    pub static ref BAND_LIMITED_SQUARE_WAVE_TABLE: BandLimitedWaveTable =
+        BandLimitedWaveTable::from_harmonics(BAND_LIMITED_TABLE_SIZE, |k| {
+            // Square wave: odd harmonics only, falling off with 1/k
+            if k % 2 == 0 {
+                0.0
+            } else {
+                1.0 / k as f64
+            }
+        });
+    pub static ref BAND_LIMITED_TRIANGLE_WAVE_TABLE: BandLimitedWaveTable =
+        BandLimitedWaveTable::from_harmonics(BAND_LIMITED_TABLE_SIZE, |k| {
+            // Triangle wave: odd harmonics only, alternating sign, falling off with 1/k^2
+            if k % 2 == 0 {
+                0.0
+            } else {
+                let sign = if k % 4 == 1 { 1.0 } else { -1.0 };
+                sign / (k * k) as f64
+            }
+        });
 }
 
 /// A Wave Table based
@@ -101,91 +133,457 @@ impl IndexMut<usize> for WaveTable {
     }
 }
 
+/// A set of octave-spaced wave tables of the same length, each containing only the harmonics
+/// that can be played without aliasing in its octave
+///
+/// Level 0 holds the most harmonics and is used for the lowest notes, every following level
+/// holds half as many harmonics as the one before, down to a pure sine in the last level.
+#[derive(Clone, Debug, Default)]
+pub struct BandLimitedWaveTable {
+    /// The tables, from most to fewest harmonics
+    tables: Arc<Vec<WaveTable>>,
+    /// The highest frequency (as a fraction of the sample rate) each table can be
+    /// played at without any of its harmonics going above nyquist
+    max_frequencies: Arc<Vec<f64>>,
+}
+
+impl BandLimitedWaveTable {
+    /// Builds a band limited wave table of the given size from a function giving the amplitude
+    /// of the sine component of each harmonic (starting from 1 for the fundamental)
+    ///
+    /// All levels are scaled by the same amount so that the level with the most harmonics peaks
+    /// at 1.0, which keeps the volume steady when moving between levels.
+    pub fn from_harmonics<F: Fn(usize) -> f64>(table_size: usize, amplitude: F) -> Self {
+        let mut tables = vec![];
+        let mut max_frequencies = vec![];
+
+        // Keep the top harmonic of the fullest table well below the table's own nyquist
+        let mut harmonics = max(table_size / 4, 1);
+        loop {
+            let mut table = vec![0.0; table_size];
+            for k in 1..=harmonics {
+                let a = amplitude(k);
+                if a == 0.0 {
+                    continue;
+                }
+                for (i, x) in table.iter_mut().enumerate() {
+                    let phase = 2.0 * std::f64::consts::PI * ((k * i) % table_size) as f64
+                        / table_size as f64;
+                    *x += a * phase.sin();
+                }
+            }
+            tables.push(WaveTable { table });
+            max_frequencies.push(0.5 / harmonics as f64);
+
+            if harmonics == 1 {
+                break;
+            }
+            harmonics /= 2;
+        }
+
+        let peak = tables[0]
+            .table
+            .iter()
+            .fold(0.0_f64, |peak, x| peak.max(x.abs()));
+        if peak > 0.0 {
+            for table in tables.iter_mut() {
+                for x in table.table.iter_mut() {
+                    *x /= peak;
+                }
+            }
+        }
+
+        BandLimitedWaveTable {
+            tables: Arc::new(tables),
+            max_frequencies: Arc::new(max_frequencies),
+        }
+    }
+
+    /// The length of each of the tables
+    pub fn len(&self) -> usize {
+        self.tables.first().map_or(0, WaveTable::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of octave levels in this table
+    pub fn levels(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Returns the table for the given level
+    pub fn level(&self, level: usize) -> &WaveTable {
+        &self.tables[level]
+    }
+
+    /// Reads the given level at the given index, interpolating linearly between samples and
+    /// crossfading by the given amount towards the next level
+    pub fn sample(&self, level: usize, crossfade: f64, index: f64) -> f64 {
+        let index0 = index as usize;
+        let index1 = if index0 + 1 >= self.len() {
+            0
+        } else {
+            index0 + 1
+        };
+        let frac = index - index0 as f64;
+
+        let table = self.level(level);
+        let mut sample = table[index0] + frac * (table[index1] - table[index0]);
+        if crossfade > 0.0 {
+            let next = self.level(level + 1);
+            let next_sample = next[index0] + frac * (next[index1] - next[index0]);
+            sample += crossfade * (next_sample - sample);
+        }
+        sample
+    }
+
+    /// Picks the tables to use for the given frequency (as a fraction of the sample rate)
+    ///
+    /// Returns the index of the level to play and how much (from 0.0 to 1.0) of the next level
+    /// to crossfade in. The next level is faded in over the top octave of each level's range, so
+    /// it has fully taken over by the time the level itself would start to alias.
+    pub fn select(&self, frequency: f64) -> (usize, f64) {
+        let last = self.levels().saturating_sub(1);
+        for (level, max_frequency) in self.max_frequencies.iter().enumerate() {
+            if frequency <= *max_frequency {
+                let fade_start = max_frequency / 2.0;
+                if level == last || frequency <= fade_start {
+                    return (level, 0.0);
+                }
+                return (level, (frequency - fade_start) / fade_start);
+            }
+        }
+        (last, 0.0)
+    }
+
+    /// Writes every level of the table to a file, one line per sample, with the levels
+    /// separated by spaces
+    pub fn dump_to_file(&self, file_name: &str) -> std::io::Result<()> {
+        let file = File::create(file_name)?;
+        let mut file = LineWriter::new(file);
+
+        for i in 0..self.len() {
+            let line: Vec<String> = self.tables.iter().map(|t| t[i].to_string()).collect();
+            writeln!(file, "{}", line.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<WaveTable> for BandLimitedWaveTable {
+    /// Wraps a single wave table, which will be used at every frequency
+    fn from(table: WaveTable) -> Self {
+        BandLimitedWaveTable {
+            tables: Arc::new(vec![table]),
+            max_frequencies: Arc::new(vec![0.5]),
+        }
+    }
+}
+
+/// How an oscillator follows another one it is synced to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+    /// Restart the wave from the beginning every time the master wraps
+    Hard,
+    /// Reverse the direction the wave is played in every time the master wraps
+    Soft,
+}
+
 /// A Wave Table oscillator
 #[derive(Clone, Debug, Default)]
 pub struct WaveTableOscillator {
-    /// frequency generated by this oscillator
-    frequency: f64,
-    /// Sample rate of the audio stream
-    sample_rate: u64,
+    /// The pitch being played
+    pitch: Pitch,
     /// Current index into wave table
     index: f64,
-    /// amount to move every sample
+    /// amount to move through the table this sample
     delta: f64,
-    /// the wave table
-    table: WaveTable,
-    /// Whether this oscillator is currently playing
-    playing: bool,
+    /// the wave tables
+    table: BandLimitedWaveTable,
+    /// the level of the band limited table being played at the current frequency
+    level: usize,
+    /// how much of the next level to mix in at the current frequency
+    crossfade: f64,
+    /// Whether the wave is being played backwards, after a soft sync
+    reversed: bool,
+    /// How far through the last step the wave wrapped around, if it did
+    wrapped: Option<f64>,
+    /// A sync to carry out during the next step
+    sync: Option<(SyncMode, f64)>,
+    /// What to add to the next sample to smooth out the last sync
+    correction: f64,
+    /// Whether the key for the current note is held down
+    held: bool,
+    /// The envelope shaping each note
+    envelope: Envelope,
 }
 
 impl WaveTableOscillator {
     pub fn new(sample_rate: u64, table: WaveTable) -> Self {
-        let mut s = Self::default();
-        s.sample_rate = sample_rate;
-        s.index = 0.0;
-        s.table = table;
-        s.playing = false;
-        s
+        Self::new_band_limited(sample_rate, table.into())
+    }
+
+    /// Creates an oscillator that switches between the levels of the given table depending on
+    /// the frequency it plays at
+    pub fn new_band_limited(sample_rate: u64, table: BandLimitedWaveTable) -> Self {
+        WaveTableOscillator {
+            pitch: Pitch::new(sample_rate),
+            index: 0.0,
+            table,
+            held: false,
+            envelope: Envelope::new(sample_rate, 0.0, 0.0, 1.0, 0.0),
+            ..Self::default()
+        }
     }
 
     pub fn set_frequency(&mut self, frequency: f64) {
-        self.frequency = frequency;
-        self.cook_frequency();
+        self.pitch.set_frequency(frequency);
+        self.select_level();
     }
 
     pub fn get_frequency(&self) -> f64 {
-        self.frequency
+        self.pitch.get_frequency()
+    }
+
+    /// Sets the pitch bend in semitones. The change is smoothed over a few milliseconds, and
+    /// the wave carries on from where it is
+    pub fn set_pitch_bend(&mut self, semitones: f64) {
+        self.pitch.set_pitch_bend(semitones);
+        self.select_level();
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch.get_pitch_bend()
+    }
+
+    /// Sets the fine tuning in cents (hundredths of a semitone), smoothed like pitch bend
+    pub fn set_detune(&mut self, cents: f64) {
+        self.pitch.set_detune(cents);
+        self.select_level();
+    }
+
+    pub fn get_detune(&self) -> f64 {
+        self.pitch.get_detune()
+    }
+
+    /// The frequency actually being played, with pitch bend and detune applied
+    pub fn get_current_frequency(&self) -> f64 {
+        self.pitch.get_current_frequency()
+    }
+
+    /// Moves to the given position in the wave, as a fraction of the period from 0.0 to 1.0
+    ///
+    /// Notes normally start from the beginning of the wave, calling this after `note_on`
+    /// starts them from somewhere else.
+    pub fn set_phase(&mut self, phase: f64) {
+        self.index = self.wrap_index(phase * self.table.len() as f64);
+    }
+
+    /// The position in the wave, as a fraction of the period from 0.0 to 1.0
+    pub fn get_phase(&self) -> f64 {
+        if self.table.is_empty() {
+            return 0.0;
+        }
+        self.index / self.table.len() as f64
     }
 
     pub fn set_sample_rate(&mut self, sample_rate: u64) {
-        self.sample_rate = sample_rate;
-        self.cook_frequency();
+        self.pitch.set_sample_rate(sample_rate);
+        self.envelope.set_sample_rate(sample_rate);
+        self.select_level();
     }
 
     pub fn get_sample_rate(&self) -> u64 {
-        self.sample_rate
+        self.pitch.get_sample_rate()
+    }
+
+    /// Picks the level of the table to play for the pitch
+    fn select_level(&mut self) {
+        let (level, crossfade) = self.table.select(self.pitch.get_normalized_frequency());
+        self.level = level;
+        self.crossfade = crossfade;
     }
 
-    fn cook_frequency(&mut self) {
-        self.delta = self.frequency * (self.table.len() as f64 / self.sample_rate as f64);
+    /// Attaches the given envelope to this oscillator, replacing the current one
+    ///
+    /// By default an oscillator has an envelope that simply gates the output on and off.
+    pub fn set_envelope(&mut self, mut envelope: Envelope) {
+        envelope.set_sample_rate(self.get_sample_rate());
+        self.envelope = envelope;
+    }
+
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn get_envelope_mut(&mut self) -> &mut Envelope {
+        &mut self.envelope
     }
 
     pub fn note_on(&mut self, frequency: f64) {
-        self.frequency = frequency;
-        self.cook_frequency();
-        self.playing = true;
-        self.index = 0.0;
+        // A new note starts at its own pitch, even if it takes over a voice that is sounding
+        self.pitch.start(frequency);
+        self.select_level();
+        // Only restart the wave if it is silent, otherwise the jump in phase clicks
+        if !self.is_playing() {
+            self.index = 0.0;
+            self.reversed = false;
+            self.correction = 0.0;
+        }
+        self.held = true;
+        self.envelope.gate_on();
     }
 
     pub fn note_off(&mut self) {
-        self.playing = false;
+        self.held = false;
+        self.envelope.gate_off();
     }
 
+    /// Whether the oscillator is making any sound, including the release of a note that has
+    /// been let go
     pub fn is_playing(&self) -> bool {
-        self.playing
+        self.envelope.is_active()
     }
 
-    pub fn step(&mut self) -> f64 {
-        if !self.playing {
-            return 0.0;
+    /// Whether the key for the current note is still held down
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    /// If the wave wrapped around to its start during the last step, returns how far (from 0.0
+    /// to 1.0) between the sample returned and the next one it happened
+    ///
+    /// This is the event to sync other oscillators to, passing the offset straight on to `sync`
+    /// before they generate their sample for the same moment. A hard sync counts as a wrap.
+    pub fn last_wrap(&self) -> Option<f64> {
+        self.wrapped
+    }
+
+    /// Syncs the oscillator to a master that wrapped the given fraction of a sample after the
+    /// next sample this oscillator generates, as returned by the master's `last_wrap`
+    ///
+    /// The sync happens part way between samples, rather than on the next one, and the jump it
+    /// makes in the wave is smoothed out with a polynomial band limited step, which keeps sync
+    /// sounds from aliasing.
+    ///
+    /// ```rust
+    /// # use sound_test::oscillator::wavetable::*;
+    /// let mut master = WaveTableOscillator::new(44100, SINE_WAVE_TABLE.clone());
+    /// let mut slave =
+    ///     WaveTableOscillator::new_band_limited(44100, BAND_LIMITED_SAW_WAVE_TABLE.clone());
+    /// master.note_on(100.0);
+    /// slave.note_on(250.0);
+    ///
+    /// // The slave is forced to repeat at the master's frequency
+    /// let mut samples = vec![];
+    /// for _ in 0..44100 {
+    ///     master.step();
+    ///     if let Some(offset) = master.last_wrap() {
+    ///         slave.sync(SyncMode::Hard, offset);
+    ///     }
+    ///     samples.push(slave.step());
+    /// }
+    /// let period = 441;
+    /// let difference = (period..44100).fold(0.0_f64, |d, i| {
+    ///     d.max((samples[i] - samples[i - period]).abs())
+    /// });
+    /// assert!(difference < 0.05);
+    /// ```
+    pub fn sync(&mut self, mode: SyncMode, offset: f64) {
+        self.sync = Some((mode, offset.clamp(0.0, 1.0)));
+    }
+
+    /// Wraps the given position into the table
+    fn wrap_index(&self, index: f64) -> f64 {
+        let index = index.rem_euclid(self.table.len() as f64);
+        // Tiny negative positions can round up to the length of the table
+        if index >= self.table.len() as f64 {
+            0.0
+        } else {
+            index
         }
+    }
 
-        let index0 = self.index as usize;
-        let index1 = if index0 == self.table.table.len() - 1 {
-            0
+    /// Moves the given distance through the wave, in whichever direction it is being played,
+    /// noting if it wraps around
+    fn advance(&mut self, distance: f64) {
+        let size = self.table.len() as f64;
+        if self.reversed {
+            self.index -= distance;
+            if self.index < 0.0 {
+                self.index += size;
+                self.wrapped = Some(1.0 - (size - self.index) / distance);
+            }
         } else {
-            index0 + 1
-        };
+            self.index += distance;
+            if self.index >= size {
+                self.index -= size;
+                self.wrapped = Some(1.0 - self.index / distance);
+            }
+        }
+        self.index = self.wrap_index(self.index);
+    }
+
+    /// Carries out a sync the given fraction of the way to the next sample, returning the
+    /// correction to add to the current sample
+    fn apply_sync(&mut self, mode: SyncMode, offset: f64) -> f64 {
+        let direction = if self.reversed { -1.0 } else { 1.0 };
+        let position = self.wrap_index(self.index + direction * offset * self.delta);
+        let before = self.table.sample(self.level, self.crossfade, position);
+
+        match mode {
+            SyncMode::Hard => {
+                // The wave jumps by this much, which the step smooths over the samples either side
+                let jump = self.table.sample(self.level, self.crossfade, 0.0) - before;
+                self.reversed = false;
+                self.index = self.wrap_index((1.0 - offset) * self.delta);
+                self.wrapped = Some(offset);
+                self.correction = -jump * offset * offset / 2.0;
+                jump * (1.0 - offset) * (1.0 - offset) / 2.0
+            }
+            SyncMode::Soft => {
+                // The wave carries on from where it is but its slope changes sign, which the
+                // integrated step smooths over the samples either side
+                let slope =
+                    self.table
+                        .sample(self.level, self.crossfade, self.wrap_index(position + 0.5))
+                        - self.table.sample(
+                            self.level,
+                            self.crossfade,
+                            self.wrap_index(position - 0.5),
+                        );
+                let bend = -2.0 * direction * slope * self.delta;
+                self.reversed = !self.reversed;
+                self.index = position;
+                self.advance((1.0 - offset) * self.delta);
+                self.wrapped = self.wrapped.map(|wrap| offset + (1.0 - offset) * wrap);
+                self.correction = bend * offset.powi(3) / 6.0;
+                bend * (1.0 - offset).powi(3) / 6.0
+            }
+        }
+    }
 
-        let frac = self.index - index0 as f64;
+    pub fn step(&mut self) -> f64 {
+        self.wrapped = None;
+        if !self.is_playing() {
+            self.sync = None;
+            return 0.0;
+        }
 
-        let sample = self.table[index0] + frac * (self.table[index1] - self.table[index0]);
+        let amplitude = self.envelope.step();
+        let mut sample =
+            self.table.sample(self.level, self.crossfade, self.index) + self.correction;
+        self.correction = 0.0;
 
-        self.index += self.delta;
-        if self.index >= self.table.len() as f64 {
-            self.index -= self.table.len() as f64;
+        self.delta = self.pitch.step() * self.table.len() as f64;
+        match self.sync.take() {
+            Some((mode, offset)) => sample += self.apply_sync(mode, offset),
+            None => self.advance(self.delta),
         }
 
-        sample
+        sample * amplitude
     }
 }
@@ -0,0 +1,138 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The storage shared between the two ends of a queue
+struct RingBuffer<T> {
+    /// The slots, one more than the capacity so a full buffer can be told apart from an empty one
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Index of the next slot to read, only written by the consumer
+    head: AtomicUsize,
+    /// Index of the next slot to write, only written by the producer
+    tail: AtomicUsize,
+}
+
+// The producer only touches slots between tail and head, and the consumer only touches slots
+// between head and tail, so the buffer can be shared as long as the items can be sent.
+unsafe impl<T: Send> Sync for RingBuffer<T> {}
+
+impl<T> RingBuffer<T> {
+    fn next(&self, index: usize) -> usize {
+        if index + 1 == self.slots.len() {
+            0
+        } else {
+            index + 1
+        }
+    }
+}
+
+impl<T> Drop for RingBuffer<T> {
+    fn drop(&mut self) {
+        // Drop anything that was pushed but never popped
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe {
+                std::ptr::drop_in_place((*self.slots[head].get()).as_mut_ptr());
+            }
+            head = self.next(head);
+        }
+    }
+}
+
+/// The sending end of a queue created with `queue`
+pub struct Producer<T> {
+    buffer: Arc<RingBuffer<T>>,
+}
+
+/// The receiving end of a queue created with `queue`
+pub struct Consumer<T> {
+    buffer: Arc<RingBuffer<T>>,
+}
+
+/// Creates a single producer, single consumer queue that can hold the given number of items
+///
+/// Neither end ever blocks or allocates after the queue is created, so one end can be used
+/// from the audio callback. `push` fails if the queue is full, and `pop` returns None if it is
+/// empty.
+///
+/// ```rust
+/// # use sound_test::queue::queue;
+/// let (mut producer, mut consumer) = queue(2);
+/// assert!(producer.push(1).is_ok());
+/// assert!(producer.push(2).is_ok());
+/// // The queue is full, so the item is given back
+/// assert_eq!(producer.push(3), Err(3));
+///
+/// let sender = std::thread::spawn(move || producer.push(4));
+/// assert_eq!(consumer.pop(), Some(1));
+/// sender.join().unwrap().ok();
+/// assert_eq!(consumer.pop(), Some(2));
+/// ```
+pub fn queue<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..=capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    let buffer = Arc::new(RingBuffer {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            buffer: buffer.clone(),
+        },
+        Consumer { buffer },
+    )
+}
+
+impl<T: Send> Producer<T> {
+    /// Adds an item to the back of the queue, or gives it back if the queue is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let tail = self.buffer.tail.load(Ordering::Relaxed);
+        let next = self.buffer.next(tail);
+        if next == self.buffer.head.load(Ordering::Acquire) {
+            return Err(item);
+        }
+
+        unsafe {
+            (*self.buffer.slots[tail].get()).as_mut_ptr().write(item);
+        }
+        self.buffer.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// The number of items the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.buffer.slots.len() - 1
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// Removes the item at the front of the queue, if there is one
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.buffer.head.load(Ordering::Relaxed);
+        if head == self.buffer.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let item = unsafe { (*self.buffer.slots[head].get()).as_ptr().read() };
+        self.buffer
+            .head
+            .store(self.buffer.next(head), Ordering::Release);
+        Some(item)
+    }
+
+    /// Whether there is nothing in the queue
+    pub fn is_empty(&self) -> bool {
+        self.buffer.head.load(Ordering::Relaxed) == self.buffer.tail.load(Ordering::Acquire)
+    }
+
+    /// The number of items the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.buffer.slots.len() - 1
+    }
+}
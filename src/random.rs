@@ -0,0 +1,53 @@
+/// The seed used by `Random::default`
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+/// # Random
+///
+/// A small, fast xorshift64* pseudo random number generator
+///
+/// This is nowhere near good enough for anything to do with security, but it is plenty for
+/// dither noise and random oscillator phases, never allocates or locks so it can be used on the
+/// audio thread, and gives the same sequence every time for a given seed.
+///
+/// ```rust
+/// # use sound_test::random::Random;
+/// let mut random = Random::new(1234);
+/// for _ in 0..1000 {
+///     let x = random.next_f64();
+///     assert!(x >= 0.0 && x < 1.0);
+/// }
+/// assert_eq!(Random::new(5).next_u64(), Random::new(5).next_u64());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::new(DEFAULT_SEED)
+    }
+}
+
+impl Random {
+    /// Creates a generator with the given seed. A seed of zero is replaced, since xorshift
+    /// would only ever return zero from it
+    pub fn new(seed: u64) -> Self {
+        Random {
+            state: if seed == 0 { DEFAULT_SEED } else { seed },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number evenly distributed between 0.0 (inclusive) and 1.0 (exclusive)
+    pub fn next_f64(&mut self) -> f64 {
+        // The top 53 bits fill the mantissa of a double exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
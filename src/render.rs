@@ -0,0 +1,181 @@
+use crate::convert::SampleConverter;
+use crate::filters::biquad::BiquadFilter;
+use crate::midi::{MidiNote, MAX_VELOCITY};
+use crate::synth::Synth;
+use crate::voice::{Voice, VoiceAllocator};
+use crate::wav::{write_wav, WavFormat, WavSpec};
+
+/// A note to play during an offline render
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScheduledNote {
+    /// The note to play
+    pub note: MidiNote,
+    /// How hard the note is played, from 1 to 127
+    pub velocity: u8,
+    /// When the note starts, in seconds from the start of the render
+    pub start: f64,
+    /// How long the note is held, in seconds
+    pub length: f64,
+}
+
+impl ScheduledNote {
+    /// Creates a ScheduledNote played at full velocity
+    pub fn new(note: MidiNote, start: f64, length: f64) -> Self {
+        Self::with_velocity(note, MAX_VELOCITY, start, length)
+    }
+
+    pub fn with_velocity(note: MidiNote, velocity: u8, start: f64, length: f64) -> Self {
+        ScheduledNote {
+            note,
+            velocity,
+            start,
+            length,
+        }
+    }
+}
+
+/// # Offline Renderer
+///
+/// Plays a list of notes through a `Synth`, as fast as possible instead of in real time, so the
+/// output can be written to a file or checked in tests.
+///
+/// The same engine is used for the live output, so a render sounds exactly like playing the
+/// notes on the keyboard.
+///
+/// ```rust
+/// # use sound_test::midi::MidiNote;
+/// # use sound_test::oscillator::wavetable::{WaveTableOscillator, SINE_WAVE_TABLE};
+/// # use sound_test::render::{OfflineRenderer, ScheduledNote};
+/// # use sound_test::voice::VoiceAllocator;
+/// let voices = vec![WaveTableOscillator::new(1000, SINE_WAVE_TABLE.clone()); 4];
+/// let mut renderer = OfflineRenderer::new(1000, VoiceAllocator::new(voices));
+///
+/// let notes = [ScheduledNote::new(MidiNote::new(60), 0.1, 0.5)];
+/// let samples = renderer.render(&notes, 1.0);
+/// assert_eq!(samples.len(), 1000);
+/// assert!(samples[..100].iter().all(|x| *x == 0.0));
+/// assert!(samples[100..600].iter().any(|x| *x != 0.0));
+/// assert!(samples[600..].iter().all(|x| *x == 0.0));
+/// ```
+#[derive(Debug)]
+pub struct OfflineRenderer<V: Voice> {
+    /// The engine the notes are played on
+    synth: Synth<V>,
+}
+
+/// A note starting or stopping at a given sample
+#[derive(Clone, Copy, Debug)]
+struct RenderEvent {
+    sample: u64,
+    on: bool,
+    note: MidiNote,
+    velocity: u8,
+}
+
+impl<V: Voice> OfflineRenderer<V> {
+    pub fn new(sample_rate: u64, voices: VoiceAllocator<V>) -> Self {
+        Self::from_synth(Synth::new(sample_rate, voices))
+    }
+
+    /// Creates an OfflineRenderer that plays notes on an existing synth
+    pub fn from_synth(synth: Synth<V>) -> Self {
+        OfflineRenderer { synth }
+    }
+
+    /// Sets the filter the mixed voices are passed through
+    pub fn set_filter(&mut self, filter: BiquadFilter) {
+        self.synth.set_filter(filter);
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.synth.get_sample_rate()
+    }
+
+    /// The voices the notes are played on
+    pub fn voices_mut(&mut self) -> &mut VoiceAllocator<V> {
+        self.synth.voices_mut()
+    }
+
+    /// Sets the number of channels to render, one (mono) by default
+    pub fn set_channels(&mut self, channels: usize) {
+        self.synth.set_channels(channels);
+    }
+
+    pub fn get_channels(&self) -> usize {
+        self.synth.get_channels()
+    }
+
+    /// The engine the notes are played on
+    pub fn synth_mut(&mut self) -> &mut Synth<V> {
+        &mut self.synth
+    }
+
+    /// Converts a time in seconds to a sample index
+    fn to_samples(&self, seconds: f64) -> u64 {
+        (seconds.max(0.0) * self.get_sample_rate() as f64).round() as u64
+    }
+
+    /// Plays the given notes for the given duration (in seconds), returning the output with the
+    /// channels interleaved
+    pub fn render(&mut self, notes: &[ScheduledNote], duration: f64) -> Vec<f64> {
+        let mut events = Vec::with_capacity(notes.len() * 2);
+        for note in notes {
+            let start = self.to_samples(note.start);
+            // Every note lasts at least a sample, otherwise its off would be sorted before its
+            // on and it would never be released
+            let end = self.to_samples(note.start + note.length).max(start + 1);
+            events.push(RenderEvent {
+                sample: start,
+                on: true,
+                note: note.note,
+                velocity: note.velocity,
+            });
+            events.push(RenderEvent {
+                sample: end,
+                on: false,
+                note: note.note,
+                velocity: 0,
+            });
+        }
+        // Note offs go before note ons at the same time, so repeated notes are retriggered
+        events.sort_by_key(|event| (event.sample, event.on));
+
+        let length = self.to_samples(duration);
+        let mut output = Vec::with_capacity(length as usize * self.get_channels());
+        let mut next_event = 0;
+        for sample in 0..length {
+            while next_event < events.len() && events[next_event].sample <= sample {
+                let event = events[next_event];
+                if event.on {
+                    self.synth.note_on(event.note, event.velocity);
+                } else {
+                    self.synth.note_off(event.note);
+                }
+                next_event += 1;
+            }
+
+            output.extend_from_slice(self.synth.next_frame());
+        }
+
+        output
+    }
+
+    /// Plays the given notes for the given duration (in seconds), and writes the output to a
+    /// WAV file in the given format, clipped and dithered by the given converter
+    pub fn render_to_file(
+        &mut self,
+        notes: &[ScheduledNote],
+        duration: f64,
+        file_name: &str,
+        format: WavFormat,
+        converter: &mut SampleConverter,
+    ) -> std::io::Result<()> {
+        let samples = self.render(notes, duration);
+        let spec = WavSpec {
+            channels: self.get_channels() as u16,
+            sample_rate: self.get_sample_rate() as u32,
+            format,
+        };
+        write_wav(file_name, spec, &samples, converter)
+    }
+}
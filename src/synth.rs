@@ -0,0 +1,420 @@
+use crate::command::Command;
+use crate::filters::biquad::BiquadFilter;
+use crate::filters::svf::StateVariableFilter;
+use crate::midi::{pitch_bend_to_normalized, MidiMessage, MidiNote, ALL_NOTES_OFF_CONTROLLER};
+use crate::mix::MixBus;
+use crate::tuning::Tuning;
+use crate::velocity::{VelocityCurve, VelocityCutoff};
+use crate::voice::{Voice, VoiceAllocator};
+
+/// The default pitch bend range, in semitones
+const DEFAULT_PITCH_BEND_RANGE: f64 = 2.0;
+
+/// The quality of the low pass filter on each voice used for velocity cutoff
+const VOICE_FILTER_QUALITY: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// Where a spread of 1.0 moves the given voice out of the given number
+///
+/// The voices take evenly spaced positions from -1.0 to 1.0, so the outermost two are always
+/// hard left and right. They alternate left and right, moving out from the center.
+fn spread_offset(voice: usize, voices: usize) -> f64 {
+    if voices < 2 {
+        return 0.0;
+    }
+    // In half steps between positions, which are odd with an even number of voices (none is
+    // in the center) and even with an odd number
+    let half_steps = voice + (voice + voices + 1) % 2;
+    let distance = half_steps as f64 / (voices - 1) as f64;
+    if voice % 2 == 1 {
+        distance
+    } else {
+        -distance
+    }
+}
+
+/// # Synth
+///
+/// The sound engine: a set of voices, the gain and pan of each voice, and a filter on the mixed
+/// output
+///
+/// The voices are panned onto a `MixBus` with as many channels as the output, and divided by
+/// the number of voices, so the output can never clip no matter how many notes are played.
+/// Each voice is added with `Voice::step_into`, so voices with a stereo image of their own,
+/// like the copies of a unison oscillator, keep it. Each channel then has its own copy of the
+/// filter. The same engine is used for live output,
+/// offline rendering and tests.
+///
+/// The velocity of each note sets the gain of its voice through the velocity curve, and if
+/// velocity cutoff is turned on, the cutoff of a low pass filter on that voice too.
+///
+/// Pitch bend moves every voice together, by up to the pitch bend range in either direction,
+/// and each voice can also be detuned by its own number of cents.
+///
+/// Each voice has its own pan position, and the spread moves voices alternately left and right
+/// of that position, further out the higher the voice number, so chords fill out the stereo
+/// field.
+///
+/// ```rust
+/// # use sound_test::midi::MidiNote;
+/// # use sound_test::oscillator::wavetable::{WaveTableOscillator, SINE_WAVE_TABLE};
+/// # use sound_test::synth::Synth;
+/// # use sound_test::voice::VoiceAllocator;
+/// let voices = vec![WaveTableOscillator::new(44100, SINE_WAVE_TABLE.clone()); 4];
+/// let mut synth = Synth::new(44100, VoiceAllocator::new(voices));
+///
+/// // Stereo output, a voice panned hard left is only heard on the first channel
+/// let mut buffer = [0.0f32; 512];
+/// synth.set_voice_pan(0, -1.0);
+/// synth.note_on(MidiNote::new(69), 127);
+/// synth.render(&mut buffer, 2);
+/// assert!(buffer.chunks(2).any(|frame| frame[0] != 0.0));
+/// assert!(buffer.chunks(2).all(|frame| frame[1] == 0.0));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Synth<V: Voice> {
+    /// Sample rate of the audio stream
+    sample_rate: u64,
+    /// The voices notes are played on
+    voices: VoiceAllocator<V>,
+    /// The gain of each voice, set from the velocity of the note it is playing
+    gains: Vec<f64>,
+    /// Maps velocity to gain
+    velocity_curve: VelocityCurve,
+    /// Maps velocity to the cutoff of each voice's filter, if the filters are used
+    velocity_cutoff: Option<VelocityCutoff>,
+    /// The low pass filter on each channel of each voice
+    voice_filters: Vec<Vec<StateVariableFilter>>,
+    /// The pan position of each voice, before the spread is added
+    pans: Vec<f64>,
+    /// How many semitones a full pitch bend moves
+    pitch_bend_range: f64,
+    /// The current pitch bend, from -1.0 to 1.0
+    pitch_bend: f64,
+    /// How far voices are spread around their pan positions, 0.0 to 1.0
+    spread: f64,
+    /// The voices are panned and summed into this
+    bus: MixBus,
+    /// Each voice is panned into this on its own, to be filtered and scaled before it is
+    /// added to `bus`
+    voice_bus: MixBus,
+    /// Filter applied to each channel of the mixed voices, empty if there is no filter
+    filters: Vec<BiquadFilter>,
+}
+
+impl<V: Voice> Synth<V> {
+    pub fn new(sample_rate: u64, voices: VoiceAllocator<V>) -> Self {
+        Synth {
+            sample_rate,
+            gains: vec![1.0; voices.len()],
+            velocity_curve: VelocityCurve::Linear,
+            velocity_cutoff: None,
+            voice_filters: vec![
+                vec![StateVariableFilter::new(
+                    sample_rate as f64 * 0.5,
+                    sample_rate as f64,
+                    VOICE_FILTER_QUALITY
+                )];
+                voices.len()
+            ],
+            pans: vec![0.0; voices.len()],
+            pitch_bend_range: DEFAULT_PITCH_BEND_RANGE,
+            pitch_bend: 0.0,
+            spread: 0.0,
+            voices,
+            bus: MixBus::new(1),
+            voice_bus: MixBus::new(1),
+            filters: vec![],
+        }
+    }
+
+    pub fn get_sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+
+    /// Sets the filter the mixed voices are passed through
+    pub fn set_filter(&mut self, filter: BiquadFilter) {
+        self.filters = vec![filter; self.bus.channels()];
+    }
+
+    /// The filter on the first channel, all channels use the same settings
+    pub fn get_filter(&self) -> Option<&BiquadFilter> {
+        self.filters.first()
+    }
+
+    /// Moves the filter to the coefficients of the given filter, using its smoothing, or uses
+    /// the given filter as is if there is no filter yet
+    pub fn retune_filter(&mut self, filter: &BiquadFilter) {
+        if self.filters.is_empty() {
+            self.set_filter(filter.clone());
+        } else {
+            for current in self.filters.iter_mut() {
+                current.retune(filter);
+            }
+        }
+    }
+
+    /// Sets the frequency of each note, for notes played from now on
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.voices.set_tuning(tuning);
+    }
+
+    pub fn get_tuning(&self) -> &Tuning {
+        self.voices.get_tuning()
+    }
+
+    /// Sets how the velocity of each note maps to the gain of its voice
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve;
+    }
+
+    pub fn get_velocity_curve(&self) -> VelocityCurve {
+        self.velocity_curve
+    }
+
+    /// Sets how the velocity of each note maps to the cutoff of a low pass filter on its voice,
+    /// or turns the voice filters off with None
+    pub fn set_velocity_cutoff(&mut self, cutoff: Option<VelocityCutoff>) {
+        self.velocity_cutoff = cutoff;
+    }
+
+    pub fn get_velocity_cutoff(&self) -> Option<VelocityCutoff> {
+        self.velocity_cutoff
+    }
+
+    /// Sets how many semitones a full pitch bend moves, up or down
+    pub fn set_pitch_bend_range(&mut self, semitones: f64) {
+        self.pitch_bend_range = semitones.abs();
+        self.apply_pitch_bend();
+    }
+
+    pub fn get_pitch_bend_range(&self) -> f64 {
+        self.pitch_bend_range
+    }
+
+    /// Bends every voice, from -1.0 (down by the pitch bend range) to 1.0 (up by the range)
+    pub fn set_pitch_bend(&mut self, bend: f64) {
+        self.pitch_bend = bend.clamp(-1.0, 1.0);
+        self.apply_pitch_bend();
+    }
+
+    pub fn get_pitch_bend(&self) -> f64 {
+        self.pitch_bend
+    }
+
+    fn apply_pitch_bend(&mut self) {
+        let semitones = self.pitch_bend * self.pitch_bend_range;
+        for voice in self.voices.voices_mut().iter_mut() {
+            voice.set_pitch_bend(semitones);
+        }
+    }
+
+    /// Tunes the given voice by the given number of cents
+    pub fn set_voice_detune(&mut self, voice: usize, cents: f64) {
+        if let Some(voice) = self.voices.voices_mut().get_mut(voice) {
+            voice.set_detune(cents);
+        }
+    }
+
+    /// Sets the number of output channels. This allocates, so it should not be done on the
+    /// audio thread, except the first time `render` is called
+    pub fn set_channels(&mut self, channels: usize) {
+        if channels.max(1) == self.bus.channels() {
+            return;
+        }
+        let width = self.bus.get_width();
+        self.bus = MixBus::new(channels);
+        self.bus.set_width(width);
+        self.voice_bus = MixBus::new(channels);
+        self.voice_bus.set_width(width);
+        if let Some(filter) = self.filters.first().cloned() {
+            self.filters = vec![filter; self.bus.channels()];
+        }
+        for filters in self.voice_filters.iter_mut() {
+            let filter = filters[0].clone();
+            filters.resize(self.bus.channels(), filter);
+        }
+    }
+
+    pub fn get_channels(&self) -> usize {
+        self.bus.channels()
+    }
+
+    /// Sets the pan position of the given voice, from -1.0 (left) to 1.0 (right)
+    pub fn set_voice_pan(&mut self, voice: usize, pan: f64) {
+        if let Some(p) = self.pans.get_mut(voice) {
+            *p = pan.clamp(-1.0, 1.0);
+        }
+    }
+
+    pub fn get_voice_pan(&self, voice: usize) -> Option<f64> {
+        self.pans.get(voice).copied()
+    }
+
+    /// Sets how far the voices are spread around their pan positions, from 0.0 (not at all)
+    /// to 1.0 (the outermost voices are panned hard left and right)
+    pub fn set_spread(&mut self, spread: f64) {
+        self.spread = spread.clamp(0.0, 1.0);
+    }
+
+    pub fn get_spread(&self) -> f64 {
+        self.spread
+    }
+
+    /// Sets the stereo width of the output, 0.0 is mono, 1.0 is normal
+    pub fn set_width(&mut self, width: f64) {
+        self.bus.set_width(width);
+        self.voice_bus.set_width(width);
+    }
+
+    pub fn get_width(&self) -> f64 {
+        self.bus.get_width()
+    }
+
+    /// The voices notes are played on
+    pub fn voices(&self) -> &VoiceAllocator<V> {
+        &self.voices
+    }
+
+    pub fn voices_mut(&mut self) -> &mut VoiceAllocator<V> {
+        &mut self.voices
+    }
+
+    /// Plays the given note with the given velocity (0 to 127), returning the voice it was
+    /// assigned to, if any
+    ///
+    /// As in MIDI, a velocity of 0 releases the note instead.
+    pub fn note_on(&mut self, note: MidiNote, velocity: u8) -> Option<usize> {
+        if velocity == 0 {
+            self.note_off(note);
+            return None;
+        }
+
+        let voice = self.voices.note_on(note)?;
+        self.gains[voice] = self.velocity_curve.apply(velocity);
+        if let Some(cutoff) = self.velocity_cutoff {
+            for filter in self.voice_filters[voice].iter_mut() {
+                filter.set_frequency(cutoff.frequency(velocity));
+            }
+        }
+        Some(voice)
+    }
+
+    /// Releases the given note
+    pub fn note_off(&mut self, note: MidiNote) {
+        self.voices.note_off(note);
+    }
+
+    /// Releases every note
+    pub fn all_notes_off(&mut self) {
+        self.voices.all_notes_off();
+    }
+
+    /// Whether any voice is making sound
+    pub fn is_playing(&self) -> bool {
+        self.voices.is_playing()
+    }
+
+    /// Carries out a command sent from another thread
+    pub fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::NoteOn(event) => {
+                self.note_on(event.note, event.velocity);
+            }
+            Command::NoteOff(note) => self.note_off(note),
+            Command::AllNotesOff => self.all_notes_off(),
+            Command::SetFilter(filter) => self.retune_filter(&filter),
+            Command::PitchBend(bend) => self.set_pitch_bend(bend),
+            Command::SetPitchBendRange(semitones) => self.set_pitch_bend_range(semitones),
+            Command::SetVoiceDetune(voice, cents) => self.set_voice_detune(voice, cents),
+            Command::SetSpread(spread) => self.set_spread(spread),
+            Command::SetWidth(width) => self.set_width(width),
+        }
+    }
+
+    /// Plays a MIDI message, on any channel. Notes, pitch bend and all notes off are
+    /// understood, anything else is ignored
+    pub fn handle_message(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } => {
+                self.note_on(note, velocity);
+            }
+            MidiMessage::NoteOff { note, .. } => self.note_off(note),
+            MidiMessage::PitchBend { value, .. } => {
+                self.set_pitch_bend(pitch_bend_to_normalized(value))
+            }
+            MidiMessage::ControlChange {
+                controller: ALL_NOTES_OFF_CONTROLLER,
+                ..
+            } => self.all_notes_off(),
+            _ => {}
+        }
+    }
+
+    /// Generates the next frame, with one sample for each channel
+    ///
+    /// ```rust
+    /// # use sound_test::midi::MidiNote;
+    /// # use sound_test::oscillator::unison::UnisonOscillator;
+    /// # use sound_test::oscillator::wavetable::BAND_LIMITED_SAW_WAVE_TABLE;
+    /// # use sound_test::synth::Synth;
+    /// # use sound_test::voice::VoiceAllocator;
+    /// let mut unison = UnisonOscillator::new(44100, BAND_LIMITED_SAW_WAVE_TABLE.clone());
+    /// unison.set_voices(8);
+    /// unison.set_spread_detune(30.0);
+    /// unison.set_stereo_spread(1.0);
+    /// let mut synth = Synth::new(44100, VoiceAllocator::new(vec![unison]));
+    /// synth.set_channels(2);
+    /// synth.note_on(MidiNote::new(57), 127);
+    ///
+    /// // A single note comes out of the two channels differently, from its spread copies
+    /// let difference: f64 = (0..44100)
+    ///     .map(|_| {
+    ///         let frame = synth.next_frame();
+    ///         (frame[0] - frame[1]).abs()
+    ///     })
+    ///     .sum();
+    /// assert!(difference > 100.0);
+    /// ```
+    pub fn next_frame(&mut self) -> &[f64] {
+        self.bus.clear();
+        let scale = 1.0 / self.voices.len().max(1) as f64;
+        for (i, voice) in self.voices.voices_mut().iter_mut().enumerate() {
+            let offset = self.spread * spread_offset(i, self.pans.len());
+            self.voice_bus.clear();
+            voice.step_into(&mut self.voice_bus, self.pans[i] + offset);
+
+            let gain = self.gains[i] * scale;
+            let channels = self.bus.frame_mut().iter_mut().zip(self.voice_bus.frame());
+            for ((out, x), filter) in channels.zip(self.voice_filters[i].iter_mut()) {
+                let mut sample = x * gain;
+                if self.velocity_cutoff.is_some() {
+                    sample = filter.step(sample).low_pass;
+                }
+                *out += sample;
+            }
+        }
+        for (x, filter) in self.bus.frame_mut().iter_mut().zip(self.filters.iter_mut()) {
+            *x = filter.step(*x);
+        }
+        self.bus.frame()
+    }
+
+    /// Generates the next frame and mixes it down to mono by averaging the channels
+    ///
+    /// The channel count is left as it is, so this is safe to call on the audio thread. A
+    /// synth set up for one channel gives exactly what `next_frame` would.
+    pub fn next_sample(&mut self) -> f64 {
+        let frame = self.next_frame();
+        frame.iter().sum::<f64>() / frame.len() as f64
+    }
+
+    /// Fills the given interleaved buffer with the given number of channels
+    pub fn render(&mut self, buffer: &mut [f32], channels: usize) {
+        self.set_channels(channels);
+        for frame in buffer.chunks_mut(self.bus.channels()) {
+            for (out, x) in frame.iter_mut().zip(self.next_frame().iter()) {
+                *out = *x as f32;
+            }
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind};
+
+use crate::midi::MidiNote;
+
+/// The number of MIDI notes
+const NOTE_COUNT: usize = 128;
+
+/// The ratios of 5 limit just intonation, from the minor second up to the octave
+const JUST_INTONATION_RATIOS: [(u32, u32); 12] = [
+    (16, 15),
+    (9, 8),
+    (6, 5),
+    (5, 4),
+    (4, 3),
+    (45, 32),
+    (3, 2),
+    (8, 5),
+    (5, 3),
+    (9, 5),
+    (15, 8),
+    (2, 1),
+];
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_text(file_name: &str) -> std::io::Result<String> {
+    let file = File::open(file_name)?;
+    let mut text = String::new();
+    BufReader::new(file).read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// The lines of a Scala file that are not comments
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.starts_with('!'))
+}
+
+/// The first word of a line, which is all that matters in most lines of a Scala file
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_number<T: std::str::FromStr>(line: Option<&str>, what: &str) -> std::io::Result<T> {
+    let line = line.ok_or_else(|| invalid(&format!("missing {}", what)))?;
+    first_word(line)
+        .parse()
+        .map_err(|_| invalid(&format!("invalid {}: {}", what, line)))
+}
+
+/// Converts a pitch from a Scala scale file to a frequency ratio: a number with a decimal point
+/// is in cents, anything else is a ratio like 3/2 or just a whole number
+fn parse_pitch(line: &str) -> std::io::Result<f64> {
+    let word = first_word(line);
+    let error = || invalid(&format!("invalid pitch: {}", line));
+    let ratio = if word.contains('.') {
+        let cents: f64 = word.parse().map_err(|_| error())?;
+        2.0f64.powf(cents / 1200.0)
+    } else {
+        let mut parts = word.splitn(2, '/');
+        let numerator: f64 = parts
+            .next()
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or_else(error)? as f64;
+        let denominator: f64 = match parts.next() {
+            Some(d) => d.parse::<u64>().map_err(|_| error())? as f64,
+            None => 1.0,
+        };
+        numerator / denominator
+    };
+    if ratio.is_finite() && ratio > 0.0 {
+        Ok(ratio)
+    } else {
+        Err(error())
+    }
+}
+
+/// # Scale
+///
+/// The pitches of one period of a scale (usually an octave), as ratios to the first note
+///
+/// The first note, 1/1, is implied, and the last ratio is the period, after which the scale
+/// repeats. This is the same layout as a Scala .scl file.
+///
+/// ```rust
+/// # use sound_test::tuning::Scale;
+/// let text = "! A comment\n\
+///     Pythagorean pentatonic\n\
+///     5\n\
+///     9/8\n\
+///     81/64\n\
+///     3/2\n\
+///     27/16\n\
+///     1200.0 cents\n";
+/// let scale = Scale::parse_scl(text).unwrap();
+/// assert_eq!(scale.len(), 5);
+/// assert_eq!(scale.degree_ratio(3), 1.5);
+/// // One period up and one degree down
+/// assert!((scale.degree_ratio(4) - 27.0 / 16.0).abs() < 1e-12);
+/// assert!((scale.degree_ratio(-1) - 27.0 / 32.0).abs() < 1e-12);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    /// The ratio of each degree after the first, the last being the period
+    pub ratios: Vec<f64>,
+}
+
+impl Scale {
+    pub fn new(description: &str, ratios: Vec<f64>) -> Self {
+        Scale {
+            description: description.to_string(),
+            ratios,
+        }
+    }
+
+    /// A scale dividing the given period (2.0 for an octave) into the given number of equal
+    /// steps
+    pub fn equal_divisions(divisions: usize, period: f64) -> Self {
+        let divisions = divisions.max(1);
+        let ratios = (1..=divisions)
+            .map(|step| period.powf(step as f64 / divisions as f64))
+            .collect();
+        Scale::new(
+            &format!("{} equal divisions of {}", divisions, period),
+            ratios,
+        )
+    }
+
+    /// The twelve note, 5 limit just intonation scale
+    pub fn just_intonation() -> Self {
+        let ratios = JUST_INTONATION_RATIOS
+            .iter()
+            .map(|&(n, d)| f64::from(n) / f64::from(d))
+            .collect();
+        Scale::new("5 limit just intonation", ratios)
+    }
+
+    /// Parses the text of a Scala scale (.scl) file
+    pub fn parse_scl(text: &str) -> std::io::Result<Self> {
+        let mut lines = scala_lines(text);
+        let description = lines
+            .next()
+            .ok_or_else(|| invalid("missing description"))?
+            .trim();
+        // The description may be blank, but any other blank line is skipped, as in .kbm files
+        let mut lines = lines.filter(|line| !line.trim().is_empty());
+        let count: usize = parse_number(lines.next(), "note count")?;
+        let ratios = lines
+            .take(count)
+            .map(parse_pitch)
+            .collect::<std::io::Result<Vec<f64>>>()?;
+        if ratios.len() != count {
+            return Err(invalid("fewer pitches than the note count"));
+        }
+
+        Ok(Scale::new(description, ratios))
+    }
+
+    /// Reads a Scala scale (.scl) file
+    pub fn read_scl(file_name: &str) -> std::io::Result<Self> {
+        Self::parse_scl(&read_text(file_name)?)
+    }
+
+    /// The number of notes in each period
+    pub fn len(&self) -> usize {
+        self.ratios.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ratios.is_empty()
+    }
+
+    /// The ratio after which the scale repeats
+    pub fn period(&self) -> f64 {
+        self.ratios.last().copied().unwrap_or(1.0)
+    }
+
+    /// The ratio of the given degree to the first note, counting on into the periods above
+    /// and below
+    pub fn degree_ratio(&self, degree: i32) -> f64 {
+        if self.is_empty() {
+            return 1.0;
+        }
+        let size = self.len() as i32;
+        let periods = degree.div_euclid(size);
+        let index = degree.rem_euclid(size) as usize;
+        let ratio = if index == 0 {
+            1.0
+        } else {
+            self.ratios[index - 1]
+        };
+        ratio * self.period().powi(periods)
+    }
+}
+
+/// # Keyboard Mapping
+///
+/// Which scale degree each MIDI note plays, and which note is tuned to a reference frequency,
+/// with the same fields as a Scala keyboard mapping (.kbm) file
+///
+/// The keys repeat every `keys.len()` notes, starting from the middle note, and each repeat is
+/// raised by the ratio of the `octave_degree`. With no keys, every note plays the next degree
+/// of the scale.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    /// The lowest note that is played
+    pub first_note: u8,
+    /// The highest note that is played
+    pub last_note: u8,
+    /// The note that plays the first degree of the scale
+    pub middle_note: u8,
+    /// The note tuned to the reference frequency
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    /// The scale degree each repeat of the keys moves up by, 0 meaning a whole period
+    pub octave_degree: usize,
+    /// The scale degree of each key, or None for keys that are not played
+    pub keys: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    /// The mapping Scala uses when there is no .kbm file: every note plays the next degree, the
+    /// scale starts at middle C and A4 is 440 Hz
+    fn default() -> Self {
+        KeyboardMapping::linear(60, 69, 440.0)
+    }
+}
+
+impl KeyboardMapping {
+    /// A mapping where every note plays the next degree of the scale
+    pub fn linear(middle_note: u8, reference_note: u8, reference_frequency: f64) -> Self {
+        KeyboardMapping {
+            first_note: 0,
+            last_note: (NOTE_COUNT - 1) as u8,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree: 0,
+            keys: vec![],
+        }
+    }
+
+    /// Parses the text of a Scala keyboard mapping (.kbm) file
+    ///
+    /// ```rust
+    /// # use sound_test::tuning::KeyboardMapping;
+    /// // A seven note scale on the white keys, with the black keys left out
+    /// let text = "12\n0\n127\n60\n69\n440.0\n7\n\
+    ///     0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+    /// let mapping = KeyboardMapping::parse_kbm(text).unwrap();
+    /// assert_eq!(mapping.keys[1], None);
+    ///
+    /// // The played range has to be made of MIDI notes, lowest first
+    /// assert!(KeyboardMapping::parse_kbm(&text.replace("\n127\n", "\n200\n")).is_err());
+    /// assert!(KeyboardMapping::parse_kbm(&text.replace("\n0\n127\n", "\n127\n0\n")).is_err());
+    /// ```
+    pub fn parse_kbm(text: &str) -> std::io::Result<Self> {
+        let mut lines = scala_lines(text).filter(|line| !line.trim().is_empty());
+        let size: usize = parse_number(lines.next(), "map size")?;
+        let first_note: u8 = parse_number(lines.next(), "first note")?;
+        let last_note: u8 = parse_number(lines.next(), "last note")?;
+        let middle_note = parse_number(lines.next(), "middle note")?;
+        let reference_note = parse_number(lines.next(), "reference note")?;
+        let reference_frequency: f64 = parse_number(lines.next(), "reference frequency")?;
+        let octave_degree = parse_number(lines.next(), "octave degree")?;
+        if reference_frequency.is_nan() || reference_frequency <= 0.0 {
+            return Err(invalid("reference frequency must be positive"));
+        }
+        if usize::from(last_note) >= NOTE_COUNT {
+            return Err(invalid(&format!(
+                "last note is not a MIDI note: {}",
+                last_note
+            )));
+        }
+        if first_note > last_note {
+            return Err(invalid("first note is above the last note"));
+        }
+
+        // Keys left off the end of the list are not played
+        let mut keys = vec![None; size];
+        for (key, line) in keys.iter_mut().zip(lines) {
+            let word = first_word(line);
+            if word != "x" {
+                let degree = word
+                    .parse()
+                    .map_err(|_| invalid(&format!("invalid key: {}", line)))?;
+                *key = Some(degree);
+            }
+        }
+
+        Ok(KeyboardMapping {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            keys,
+        })
+    }
+
+    /// Reads a Scala keyboard mapping (.kbm) file
+    pub fn read_kbm(file_name: &str) -> std::io::Result<Self> {
+        Self::parse_kbm(&read_text(file_name)?)
+    }
+
+    /// The ratio of the given note to the middle note in the given scale, or None if the note
+    /// is not mapped to a degree
+    fn note_ratio(&self, scale: &Scale, note: u8) -> Option<f64> {
+        let offset = i32::from(note) - i32::from(self.middle_note);
+        if self.keys.is_empty() {
+            return Some(scale.degree_ratio(offset));
+        }
+
+        let size = self.keys.len() as i32;
+        let repeats = offset.div_euclid(size);
+        let degree = self.keys[offset.rem_euclid(size) as usize]?;
+        let octave_degree = match self.octave_degree {
+            0 => scale.len(),
+            degree => degree,
+        };
+        let repeat_ratio = scale.degree_ratio(octave_degree as i32);
+        Some(scale.degree_ratio(degree as i32) * repeat_ratio.powi(repeats))
+    }
+}
+
+/// # Tuning
+///
+/// The frequency of every MIDI note, worked out from a `Scale` and a `KeyboardMapping`
+///
+/// Notes outside of the mapped range, or on keys the mapping leaves out, have no frequency and
+/// are not played. The default tuning is 12 tone equal temperament with A4 at 440 Hz.
+///
+/// ```rust
+/// # use sound_test::midi::MidiNote;
+/// # use sound_test::tuning::Tuning;
+/// // 19 equal divisions of the octave, with A4 at 440 Hz
+/// let tuning = Tuning::equal_temperament(19, MidiNote::new(69), 440.0);
+/// assert_eq!(tuning.to_frequency(MidiNote::new(69)), Some(440.0));
+/// let octave_up = tuning.to_frequency(MidiNote::new(69 + 19)).unwrap();
+/// assert!((octave_up - 880.0).abs() < 1e-9);
+///
+/// // Just intonation starting from C4, so G4 is a perfect fifth above it
+/// let c4 = MidiNote::new(60).to_frequency();
+/// let tuning = Tuning::just_intonation(MidiNote::new(60), c4);
+/// let g4 = tuning.to_frequency(MidiNote::new(67)).unwrap();
+/// assert!((g4 / c4 - 1.5).abs() < 1e-12);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    /// The frequency of each MIDI note, or None if it is not played
+    frequencies: [Option<f64>; NOTE_COUNT],
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        let mut frequencies = [None; NOTE_COUNT];
+        for (note, frequency) in frequencies.iter_mut().enumerate() {
+            *frequency = Some(MidiNote::new(note as u8).to_frequency());
+        }
+        Tuning { frequencies }
+    }
+}
+
+impl Tuning {
+    /// Tunes the notes of the given mapping to the given scale. Fails if the reference note is
+    /// on a key that is not mapped, since there is then nothing to tune the scale against
+    pub fn new(scale: &Scale, mapping: &KeyboardMapping) -> std::io::Result<Self> {
+        let reference = mapping
+            .note_ratio(scale, mapping.reference_note)
+            .ok_or_else(|| invalid("the reference note is not mapped"))?;
+
+        let mut frequencies = [None; NOTE_COUNT];
+        let notes = mapping.first_note..=mapping.last_note;
+        for (note, frequency) in frequencies.iter_mut().enumerate() {
+            let note = note as u8;
+            if notes.contains(&note) {
+                *frequency = mapping
+                    .note_ratio(scale, note)
+                    .map(|ratio| mapping.reference_frequency * ratio / reference);
+            }
+        }
+
+        Ok(Tuning { frequencies })
+    }
+
+    /// Equal divisions of the octave, with every note one step apart and the given note tuned
+    /// to the given frequency
+    pub fn equal_temperament(divisions: usize, reference: MidiNote, frequency: f64) -> Self {
+        let scale = Scale::equal_divisions(divisions, 2.0);
+        let mapping = KeyboardMapping::linear(reference.number(), reference.number(), frequency);
+        Self::new(&scale, &mapping).expect("a linear mapping maps every note")
+    }
+
+    /// 5 limit just intonation, built up from the given tonic at the given frequency
+    pub fn just_intonation(tonic: MidiNote, frequency: f64) -> Self {
+        let scale = Scale::just_intonation();
+        let mapping = KeyboardMapping::linear(tonic.number(), tonic.number(), frequency);
+        Self::new(&scale, &mapping).expect("a linear mapping maps every note")
+    }
+
+    /// Reads a Scala scale file, and optionally a keyboard mapping file. Without a mapping, the
+    /// scale starts at middle C and A4 is tuned to 440 Hz
+    pub fn from_scala_files(scl_file: &str, kbm_file: Option<&str>) -> std::io::Result<Self> {
+        let scale = Scale::read_scl(scl_file)?;
+        let mapping = match kbm_file {
+            Some(file_name) => KeyboardMapping::read_kbm(file_name)?,
+            None => KeyboardMapping::default(),
+        };
+        Self::new(&scale, &mapping)
+    }
+
+    /// The frequency of the given note, or None if it is not played in this tuning
+    pub fn to_frequency(&self, note: MidiNote) -> Option<f64> {
+        self.frequencies.get(usize::from(note.number())).copied()?
+    }
+}
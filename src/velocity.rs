@@ -0,0 +1,80 @@
+use crate::midi::MAX_VELOCITY;
+
+/// How the velocity of a note is turned into a level from 0.0 to 1.0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VelocityCurve {
+    /// The level is proportional to the velocity
+    Linear,
+    /// The level rises by the same number of decibels for each step of velocity, covering the
+    /// given dynamic range in dB between the softest and hardest notes
+    Exponential(f64),
+    /// Every note gets the given level, whatever its velocity
+    Fixed(f64),
+}
+
+impl VelocityCurve {
+    /// Maps the given velocity (1 to 127) to a level
+    ///
+    /// ```rust
+    /// # use sound_test::velocity::VelocityCurve;
+    /// assert_eq!(VelocityCurve::Linear.apply(127), 1.0);
+    /// assert_eq!(VelocityCurve::Fixed(0.8).apply(10), 0.8);
+    ///
+    /// // Every 63 steps of velocity is 20 dB, a factor of 10
+    /// let curve = VelocityCurve::Exponential(40.0);
+    /// assert_eq!(curve.apply(127), 1.0);
+    /// assert!((curve.apply(64) - 0.1).abs() < 1e-12);
+    /// assert!((curve.apply(1) - 0.01).abs() < 1e-12);
+    /// ```
+    pub fn apply(self, velocity: u8) -> f64 {
+        let velocity = velocity.min(MAX_VELOCITY);
+        match self {
+            VelocityCurve::Linear => f64::from(velocity) / f64::from(MAX_VELOCITY),
+            VelocityCurve::Exponential(range_db) => {
+                if velocity == 0 {
+                    return 0.0;
+                }
+                let position = f64::from(velocity - 1) / f64::from(MAX_VELOCITY - 1);
+                10.0f64.powf(range_db * (position - 1.0) / 20.0)
+            }
+            VelocityCurve::Fixed(level) => level,
+        }
+    }
+}
+
+/// How the velocity of a note opens up the filter on its voice
+///
+/// The level from the curve moves the cutoff between the lowest and highest frequency, evenly
+/// in octaves, so soft notes are darker than hard ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityCutoff {
+    pub curve: VelocityCurve,
+    /// The cutoff for a level of 0.0
+    pub min_frequency: f64,
+    /// The cutoff for a level of 1.0
+    pub max_frequency: f64,
+}
+
+impl VelocityCutoff {
+    pub fn new(curve: VelocityCurve, min_frequency: f64, max_frequency: f64) -> Self {
+        VelocityCutoff {
+            curve,
+            min_frequency,
+            max_frequency,
+        }
+    }
+
+    /// The cutoff frequency for the given velocity
+    ///
+    /// ```rust
+    /// # use sound_test::velocity::{VelocityCurve, VelocityCutoff};
+    /// let cutoff = VelocityCutoff::new(VelocityCurve::Linear, 500.0, 8000.0);
+    /// assert!((cutoff.frequency(127) - 8000.0).abs() < 1e-9);
+    /// assert!(cutoff.frequency(64) > 1900.0 && cutoff.frequency(64) < 2100.0);
+    /// ```
+    pub fn frequency(&self, velocity: u8) -> f64 {
+        let min = self.min_frequency.max(f64::EPSILON);
+        let max = self.max_frequency.max(min);
+        min * (max / min).powf(self.curve.apply(velocity).clamp(0.0, 1.0))
+    }
+}
@@ -0,0 +1,404 @@
+use crate::midi::MidiNote;
+use crate::mix::MixBus;
+use crate::oscillator::fm::FmOscillator;
+use crate::oscillator::polyblep::PolyBlepOscillator;
+use crate::oscillator::pulse::PulseOscillator;
+use crate::oscillator::sync::SyncedOscillator;
+use crate::oscillator::unison::UnisonOscillator;
+use crate::oscillator::wavetable::WaveTableOscillator;
+use crate::tuning::Tuning;
+
+/// Something that can play one note at a time, and be managed by a `VoiceAllocator`
+pub trait Voice {
+    /// Starts playing a note at the given frequency
+    fn note_on(&mut self, frequency: f64);
+    /// Releases the current note
+    fn note_off(&mut self);
+    /// Changes the frequency of the current note without restarting it
+    fn set_frequency(&mut self, frequency: f64);
+    /// Bends the pitch of the voice by the given number of semitones, on top of the note
+    fn set_pitch_bend(&mut self, semitones: f64);
+    /// Tunes the voice by the given number of cents, on top of the note and pitch bend
+    fn set_detune(&mut self, cents: f64);
+    /// Whether the voice is making any sound, including the release of a note
+    fn is_playing(&self) -> bool;
+    /// How loud the voice currently is, used to find the quietest voice to steal
+    fn get_amplitude(&self) -> f64;
+    /// Generates the next output sample
+    fn step(&mut self) -> f64;
+    /// Generates the next output sample and adds it to the bus at the given pan position.
+    /// Voices with a stereo image of their own spread themselves around that position
+    fn step_into(&mut self, bus: &mut MixBus, pan: f64) {
+        bus.add(self.step(), pan);
+    }
+}
+
+/// Implements `Voice` for an oscillator by calling its own methods of the same names. Its
+/// amplitude is the level of its envelope, unless an expression taking the oscillator is given.
+/// Oscillators marked `stereo` use their own `step_into` rather than panning `step`
+macro_rules! impl_voice {
+    (@impl $oscillator:ty, $amplitude:expr, { $($extra:tt)* }) => {
+        impl Voice for $oscillator {
+            fn note_on(&mut self, frequency: f64) {
+                <$oscillator>::note_on(self, frequency);
+            }
+
+            fn note_off(&mut self) {
+                <$oscillator>::note_off(self);
+            }
+
+            fn set_frequency(&mut self, frequency: f64) {
+                <$oscillator>::set_frequency(self, frequency);
+            }
+
+            fn set_pitch_bend(&mut self, semitones: f64) {
+                <$oscillator>::set_pitch_bend(self, semitones);
+            }
+
+            fn set_detune(&mut self, cents: f64) {
+                <$oscillator>::set_detune(self, cents);
+            }
+
+            fn is_playing(&self) -> bool {
+                <$oscillator>::is_playing(self)
+            }
+
+            fn get_amplitude(&self) -> f64 {
+                ($amplitude)(self)
+            }
+
+            fn step(&mut self) -> f64 {
+                <$oscillator>::step(self)
+            }
+
+            $($extra)*
+        }
+    };
+    (@envelope $oscillator:ty) => {
+        |oscillator: &$oscillator| oscillator.get_envelope().get_level()
+    };
+    ($oscillator:ty, stereo) => {
+        impl_voice!(@impl $oscillator, impl_voice!(@envelope $oscillator), {
+            fn step_into(&mut self, bus: &mut MixBus, pan: f64) {
+                <$oscillator>::step_into(self, bus, pan);
+            }
+        });
+    };
+    ($oscillator:ty) => {
+        impl_voice!(@impl $oscillator, impl_voice!(@envelope $oscillator), {});
+    };
+    ($oscillator:ty, $amplitude:expr) => {
+        impl_voice!(@impl $oscillator, $amplitude, {});
+    };
+}
+
+impl_voice!(WaveTableOscillator);
+impl_voice!(PolyBlepOscillator);
+impl_voice!(PulseOscillator);
+impl_voice!(SyncedOscillator);
+impl_voice!(UnisonOscillator, stereo);
+impl_voice!(FmOscillator, FmOscillator::get_amplitude);
+
+/// Which voice to take over when a note is played and every voice is busy
+///
+/// Voices whose notes have been released are always stolen before voices that are still held.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicy {
+    /// Never steal a held voice, the new note is dropped (released voices are still reused,
+    /// oldest first)
+    None,
+    /// Steal the voice that started playing first
+    Oldest,
+    /// Steal the voice with the lowest amplitude
+    Quietest,
+    /// Steal the voice playing the lowest note
+    Lowest,
+    /// Steal the voice playing the highest note
+    Highest,
+}
+
+/// How notes are assigned to voices
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceMode {
+    /// Each note gets its own voice
+    Poly,
+    /// Only one note plays at a time, and every new note restarts the voice
+    Mono,
+    /// Only one note plays at a time, and a note played while another is held just changes the
+    /// pitch without restarting the voice
+    Legato,
+}
+
+/// Book keeping for each voice
+#[derive(Clone, Copy, Debug, Default)]
+struct VoiceSlot {
+    /// The note the voice was last given
+    note: Option<MidiNote>,
+    /// Whether the key for that note is still held down
+    held: bool,
+    /// When the note started, used to find the oldest voice
+    started: u64,
+}
+
+/// The number of different MIDI notes, which is the most that can be held at once
+const MAX_HELD_NOTES: usize = 128;
+
+/// # Voice Allocator
+///
+/// Assigns notes to a fixed set of voices, keeping track of which voice is playing which note
+///
+/// In `VoiceMode::Poly`, a note that is already playing (even if it is releasing) is retriggered
+/// on the same voice, otherwise it goes to a silent voice, or if there is none, to the voice
+/// picked by the `StealPolicy`. In `VoiceMode::Mono` and `VoiceMode::Legato` only the first voice
+/// is used, and letting go of a note goes back to the most recent note still held.
+///
+/// The frequency of each note comes from the allocator's `Tuning`, and notes the tuning does
+/// not play are dropped.
+///
+/// ```rust
+/// # use sound_test::midi::MidiNote;
+/// # use sound_test::oscillator::wavetable::{WaveTableOscillator, SINE_WAVE_TABLE};
+/// # use sound_test::voice::{StealPolicy, VoiceAllocator};
+/// let voices = vec![WaveTableOscillator::new(44100, SINE_WAVE_TABLE.clone()); 2];
+/// let mut allocator = VoiceAllocator::new(voices);
+/// allocator.set_steal_policy(StealPolicy::Oldest);
+///
+/// assert_eq!(allocator.note_on(MidiNote::new(60)), Some(0));
+/// assert_eq!(allocator.note_on(MidiNote::new(64)), Some(1));
+/// // Both voices are busy, so the oldest one is stolen
+/// assert_eq!(allocator.note_on(MidiNote::new(67)), Some(0));
+/// assert_eq!(allocator.get_note(0), Some(MidiNote::new(67)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator<V: Voice> {
+    /// The voices
+    voices: Vec<V>,
+    /// Book keeping for each voice
+    slots: Vec<VoiceSlot>,
+    /// Which voice to take over when every voice is busy
+    steal_policy: StealPolicy,
+    /// How notes are assigned to voices
+    mode: VoiceMode,
+    /// Notes held down in mono and legato modes, most recent last
+    held_notes: Vec<MidiNote>,
+    /// Counts note ons, used to order voices by age
+    counter: u64,
+    /// The frequency of each note
+    tuning: Tuning,
+}
+
+impl<V: Voice> VoiceAllocator<V> {
+    /// Creates a new polyphonic allocator managing the given voices, that steals the oldest voice
+    /// when they are all busy
+    pub fn new(voices: Vec<V>) -> Self {
+        let slots = vec![VoiceSlot::default(); voices.len()];
+        VoiceAllocator {
+            voices,
+            slots,
+            steal_policy: StealPolicy::Oldest,
+            mode: VoiceMode::Poly,
+            held_notes: Vec::with_capacity(MAX_HELD_NOTES),
+            counter: 0,
+            tuning: Tuning::default(),
+        }
+    }
+
+    /// Sets the frequency of each note, for notes played from now on
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+    }
+
+    pub fn get_tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    /// The frequency of a note that has already been checked against the tuning
+    fn frequency(&self, note: MidiNote) -> f64 {
+        self.tuning
+            .to_frequency(note)
+            .unwrap_or_else(|| note.to_frequency())
+    }
+
+    pub fn set_steal_policy(&mut self, steal_policy: StealPolicy) {
+        self.steal_policy = steal_policy;
+    }
+
+    pub fn get_steal_policy(&self) -> StealPolicy {
+        self.steal_policy
+    }
+
+    /// Changes how notes are assigned to voices, releasing every note
+    pub fn set_mode(&mut self, mode: VoiceMode) {
+        self.all_notes_off();
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> VoiceMode {
+        self.mode
+    }
+
+    /// The voices managed by this allocator
+    pub fn voices(&self) -> &[V] {
+        &self.voices
+    }
+
+    /// The voices managed by this allocator
+    pub fn voices_mut(&mut self) -> &mut [V] {
+        &mut self.voices
+    }
+
+    /// The number of voices
+    pub fn len(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The note last played on the given voice, if it is still playing
+    pub fn get_note(&self, voice: usize) -> Option<MidiNote> {
+        if self.voices[voice].is_playing() {
+            self.slots[voice].note
+        } else {
+            None
+        }
+    }
+
+    /// Whether the key for the note on the given voice is still held down
+    pub fn is_held(&self, voice: usize) -> bool {
+        self.slots[voice].held
+    }
+
+    /// Whether any voice is making sound
+    pub fn is_playing(&self) -> bool {
+        self.voices.iter().any(V::is_playing)
+    }
+
+    /// Plays the given note, returning the voice it was assigned to, or None if it was dropped
+    pub fn note_on(&mut self, note: MidiNote) -> Option<usize> {
+        if self.voices.is_empty() || self.tuning.to_frequency(note).is_none() {
+            return None;
+        }
+
+        match self.mode {
+            VoiceMode::Poly => self.poly_note_on(note),
+            VoiceMode::Mono | VoiceMode::Legato => {
+                self.held_notes.retain(|n| *n != note);
+                if self.held_notes.len() == MAX_HELD_NOTES {
+                    self.held_notes.remove(0);
+                }
+                self.held_notes.push(note);
+                self.mono_play(note);
+                Some(0)
+            }
+        }
+    }
+
+    /// Releases the given note
+    pub fn note_off(&mut self, note: MidiNote) {
+        match self.mode {
+            VoiceMode::Poly => {
+                for (voice, slot) in self.voices.iter_mut().zip(self.slots.iter_mut()) {
+                    if slot.held && slot.note == Some(note) {
+                        voice.note_off();
+                        slot.held = false;
+                    }
+                }
+            }
+            VoiceMode::Mono | VoiceMode::Legato => {
+                self.held_notes.retain(|n| *n != note);
+                if self.slots.is_empty() || self.slots[0].note != Some(note) {
+                    return;
+                }
+                // Go back to the last note still held, if there is one
+                match self.held_notes.last() {
+                    Some(&previous) => self.mono_play(previous),
+                    None => {
+                        self.voices[0].note_off();
+                        self.slots[0].held = false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Releases every note
+    pub fn all_notes_off(&mut self) {
+        self.held_notes.clear();
+        for (voice, slot) in self.voices.iter_mut().zip(self.slots.iter_mut()) {
+            if slot.held {
+                voice.note_off();
+                slot.held = false;
+            }
+        }
+    }
+
+    /// Starts the given note on the given voice
+    fn start_voice(&mut self, voice: usize, note: MidiNote) {
+        self.counter += 1;
+        self.slots[voice] = VoiceSlot {
+            note: Some(note),
+            held: true,
+            started: self.counter,
+        };
+        let frequency = self.frequency(note);
+        self.voices[voice].note_on(frequency);
+    }
+
+    fn poly_note_on(&mut self, note: MidiNote) -> Option<usize> {
+        // Retrigger the voice already playing this note
+        let same_note = (0..self.len()).find(|&i| self.get_note(i) == Some(note));
+        let voice = same_note
+            .or_else(|| (0..self.len()).find(|&i| !self.voices[i].is_playing()))
+            .or_else(|| self.pick_voice_to_steal())?;
+
+        self.start_voice(voice, note);
+        Some(voice)
+    }
+
+    /// Plays the given note on the mono voice, gliding to it in legato mode if a note is held
+    fn mono_play(&mut self, note: MidiNote) {
+        if self.mode == VoiceMode::Legato && self.slots[0].held {
+            self.slots[0].note = Some(note);
+            let frequency = self.frequency(note);
+            self.voices[0].set_frequency(frequency);
+        } else {
+            self.start_voice(0, note);
+        }
+    }
+
+    /// Picks the voice to steal according to the steal policy, preferring released voices
+    fn pick_voice_to_steal(&self) -> Option<usize> {
+        let released = (0..self.len()).filter(|&i| !self.slots[i].held);
+        let policy = match self.steal_policy {
+            StealPolicy::None => StealPolicy::Oldest,
+            policy => policy,
+        };
+        let candidate = self.best_by_policy(released, policy);
+        if candidate.is_some() {
+            return candidate;
+        }
+        self.best_by_policy(0..self.len(), self.steal_policy)
+    }
+
+    /// Picks the best of the given voices to steal according to the given policy
+    fn best_by_policy<I>(&self, voices: I, policy: StealPolicy) -> Option<usize>
+    where
+        I: Iterator<Item = usize>,
+    {
+        let note = |i: usize| self.slots[i].note.map_or(0, |n| n.number());
+        match policy {
+            StealPolicy::None => None,
+            StealPolicy::Oldest => voices.min_by_key(|&i| self.slots[i].started),
+            StealPolicy::Lowest => voices.min_by_key(|&i| note(i)),
+            StealPolicy::Highest => voices.max_by_key(|&i| note(i)),
+            StealPolicy::Quietest => voices.min_by(|&a, &b| {
+                self.voices[a]
+                    .get_amplitude()
+                    .partial_cmp(&self.voices[b].get_amplitude())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+    }
+}
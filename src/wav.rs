@@ -0,0 +1,254 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+
+use crate::convert::{OutputSample, SampleConverter, I24};
+
+/// The sample formats that can be written to a WAV file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavFormat {
+    /// 16 bit signed integer PCM
+    Int16,
+    /// 24 bit signed integer PCM
+    Int24,
+    /// 32 bit IEEE float
+    Float32,
+}
+
+impl WavFormat {
+    /// The number of bytes in one sample
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            WavFormat::Int16 => 2,
+            WavFormat::Int24 => 3,
+            WavFormat::Float32 => 4,
+        }
+    }
+
+    /// The value of the format tag in the fmt chunk
+    fn format_tag(self) -> u16 {
+        match self {
+            WavFormat::Int16 | WavFormat::Int24 => WAVE_FORMAT_PCM,
+            WavFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Description of the audio in a WAV file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub format: WavFormat,
+}
+
+/// Writes the given interleaved samples (nominally between -1.0 and 1.0) to a WAV file, clipped
+/// and dithered by the given converter
+pub fn write_wav(
+    file_name: &str,
+    spec: WavSpec,
+    samples: &[f64],
+    converter: &mut SampleConverter,
+) -> std::io::Result<()> {
+    let file = File::create(file_name)?;
+    let mut file = BufWriter::new(file);
+    write_wav_to(&mut file, spec, samples, converter)?;
+    file.flush()
+}
+
+/// Writes the given interleaved samples as a complete WAV file to the given writer
+///
+/// Every sample goes through the converter, which should have as many channels as the spec, so
+/// the file is clipped, dithered and noise shaped just like live output.
+///
+/// Fails with `InvalidInput` if there are too many samples for the sizes in the RIFF header,
+/// which are limited to 4 GiB.
+///
+/// ```rust
+/// # use sound_test::convert::SampleConverter;
+/// # use sound_test::wav::{parse_wav, write_wav_to, WavFormat, WavSpec};
+/// let spec = WavSpec {
+///     channels: 2,
+///     sample_rate: 44100,
+///     format: WavFormat::Int24,
+/// };
+/// let mut converter = SampleConverter::new(2);
+/// let mut bytes = vec![];
+/// write_wav_to(&mut bytes, spec, &[0.0, 0.5, -0.5, 2.0], &mut converter).unwrap();
+///
+/// let (read_spec, samples) = parse_wav(&bytes).unwrap();
+/// assert_eq!(read_spec, spec);
+/// assert_eq!(samples[1], 0.5);
+/// assert_eq!(samples[2], -0.5);
+/// // Out of range samples are clipped
+/// assert!((samples[3] - 1.0).abs() < 1e-6);
+/// ```
+pub fn write_wav_to<W: Write>(
+    writer: &mut W,
+    spec: WavSpec,
+    samples: &[f64],
+    converter: &mut SampleConverter,
+) -> std::io::Result<()> {
+    let bytes_per_sample = spec.format.bytes_per_sample();
+    let is_float = spec.format == WavFormat::Float32;
+    // Non PCM formats need the extension size in the fmt chunk, and a fact chunk
+    let fmt_size: u32 = if is_float { 18 } else { 16 };
+    let fact_size: u32 = if is_float { 12 } else { 0 };
+    let too_long = || Error::new(ErrorKind::InvalidInput, "too much audio for a WAV file");
+    let data_size = samples
+        .len()
+        .checked_mul(bytes_per_sample)
+        .and_then(|size| u32::try_from(size).ok())
+        .ok_or_else(too_long)?;
+    // Chunks have to be an even number of bytes long
+    let padding = data_size & 1;
+    let riff_size = (4 + (8 + fmt_size) + fact_size + 8)
+        .checked_add(data_size)
+        .and_then(|size| size.checked_add(padding))
+        .ok_or_else(too_long)?;
+
+    let block_align = spec.channels * bytes_per_sample as u16;
+    let byte_rate = spec.sample_rate * u32::from(block_align);
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&riff_size.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&fmt_size.to_le_bytes())?;
+    writer.write_all(&spec.format.format_tag().to_le_bytes())?;
+    writer.write_all(&spec.channels.to_le_bytes())?;
+    writer.write_all(&spec.sample_rate.to_le_bytes())?;
+    writer.write_all(&byte_rate.to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&(8 * bytes_per_sample as u16).to_le_bytes())?;
+    if is_float {
+        writer.write_all(&0u16.to_le_bytes())?;
+
+        // Each sample is at least a byte, so this fits if the data size did
+        let frames = (samples.len() / usize::from(spec.channels.max(1))) as u32;
+        writer.write_all(b"fact")?;
+        writer.write_all(&4u32.to_le_bytes())?;
+        writer.write_all(&frames.to_le_bytes())?;
+    }
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    let channels = usize::from(spec.channels.max(1));
+    for (i, sample) in samples.iter().enumerate() {
+        let channel = i % channels;
+        match spec.format {
+            WavFormat::Int16 => {
+                let value: i16 = converter.convert_sample(*sample, channel);
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            WavFormat::Int24 => {
+                let value: I24 = converter.convert_sample(*sample, channel);
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            WavFormat::Float32 => {
+                let value: f32 = converter.convert_sample(*sample, channel);
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    if padding != 0 {
+        writer.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+/// Reads a WAV file written in one of the supported formats, returning its description and the
+/// interleaved samples scaled to between -1.0 and 1.0
+pub fn read_wav(file_name: &str) -> std::io::Result<(WavSpec, Vec<f64>)> {
+    let file = File::open(file_name)?;
+    let mut bytes = vec![];
+    BufReader::new(file).read_to_end(&mut bytes)?;
+    parse_wav(&bytes)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Parses the bytes of a complete WAV file
+pub fn parse_wav(bytes: &[u8]) -> std::io::Result<(WavSpec, Vec<f64>)> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF WAVE file"));
+    }
+
+    let mut spec = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let id = &bytes[position..position + 4];
+        let size = read_u32(bytes, position + 4) as usize;
+        let body = position + 8;
+        if body + size > bytes.len() {
+            return Err(invalid("chunk runs past the end of the file"));
+        }
+
+        match id {
+            b"fmt " => {
+                if size < 16 {
+                    return Err(invalid("fmt chunk is too short"));
+                }
+                let tag = read_u16(bytes, body);
+                let channels = read_u16(bytes, body + 2);
+                let sample_rate = read_u32(bytes, body + 4);
+                let bits = read_u16(bytes, body + 14);
+                let format = match (tag, bits) {
+                    (WAVE_FORMAT_PCM, 16) => WavFormat::Int16,
+                    (WAVE_FORMAT_PCM, 24) => WavFormat::Int24,
+                    (WAVE_FORMAT_IEEE_FLOAT, 32) => WavFormat::Float32,
+                    _ => return Err(invalid("unsupported sample format")),
+                };
+                spec = Some(WavSpec {
+                    channels,
+                    sample_rate,
+                    format,
+                });
+            }
+            b"data" => {
+                let spec = spec.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                let data = &bytes[body..body + size];
+                let samples = match spec.format {
+                    WavFormat::Int16 => data
+                        .chunks_exact(2)
+                        .map(|b| f64::from(i16::from_le_bytes([b[0], b[1]])) / i16::SCALE)
+                        .collect(),
+                    WavFormat::Int24 => data
+                        .chunks_exact(3)
+                        .map(|b| {
+                            // Put the 24 bits at the top of an i32 to sign extend them
+                            let value = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+                            f64::from(value) / I24::SCALE
+                        })
+                        .collect(),
+                    WavFormat::Float32 => data
+                        .chunks_exact(4)
+                        .map(|b| f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+                        .collect(),
+                };
+                return Ok((spec, samples));
+            }
+            _ => (),
+        }
+
+        position = body + size + (size & 1);
+    }
+
+    Err(invalid("no data chunk"))
+}